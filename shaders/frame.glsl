@@ -0,0 +1,26 @@
+// What every shader may read each frame, shared by all programs through uniform buffers.
+// Filled in by `src/uniform_buffer.rs`, which must match these declarations.
+
+const int MAX_LIGHTS = 8;
+
+layout(std140, binding = 0) uniform Camera
+{
+    mat4 view;
+    mat4 projection;
+    mat4 viewProjection;
+    vec3 cameraPosition;
+    float time; // simulated seconds
+};
+
+struct Light
+{
+    vec4 position;  // w is 1 for point lights and 0 for directional ones
+    vec4 direction; // the way a directional light shines
+    vec4 color;     // times the intensity
+};
+
+layout(std140, binding = 1) uniform Lights
+{
+    int lightCount;
+    Light lights[MAX_LIGHTS];
+};
@@ -0,0 +1,19 @@
+// Lighting shared between shaders, with #include "lighting.glsl"
+
+#include "frame.glsl"
+
+// The light from every light in the scene reaching a surface at `position` facing along `normal`.
+// Point lights shine as brightly however far away they are.
+vec3 diffuse(vec3 position, vec3 normal)
+{
+    vec3 surfaceNormal = normalize(normal);
+    vec3 light = vec3(0.0f);
+    for (int i = 0; i < lightCount; i++)
+    {
+        vec3 toLight = lights[i].position.w > 0.5f
+            ? normalize(lights[i].position.xyz - position)
+            : -lights[i].direction.xyz;
+        light += lights[i].color.rgb * max(dot(surfaceNormal, toLight), 0);
+    }
+    return light;
+}
@@ -1,6 +1,6 @@
 #version 430 core
 
-uniform layout(location = 0) mat4x4 transPos;
+#include "frame.glsl"
 
 layout (location=0) in vec3 position;
 
@@ -8,15 +8,23 @@ layout (location=1) in vec4 vColor;
 
 layout (location=2) in vec3 vNormal;
 
+// per instance, see `src/renderer.rs`
+layout (location=3) in mat4x4 instanceModel;
+
+layout (location=7) in vec4 instanceColor;
+
 layout (location=1) out vec4 vertexColor;
 
 layout (location=2) out vec3 vertexNormal;
 
+layout (location=3) out vec3 vertexPosition;
+
 
 void main()
 {
-    vec4 pos = vec4(position, 1.0f);
-    gl_Position = transPos * pos ;
-    vertexColor = vColor;
-    vertexNormal = vNormal;
-}
\ No newline at end of file
+    vec4 pos = instanceModel * vec4(position, 1.0f);
+    gl_Position = viewProjection * pos ;
+    vertexColor = vColor * instanceColor;
+    vertexNormal = mat3(instanceModel) * vNormal;
+    vertexPosition = pos.xyz;
+}
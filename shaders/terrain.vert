@@ -0,0 +1,28 @@
+#version 430 core
+
+// Passes the terrain on to `terrain.tcs` in world space, see `src/terrain.rs`
+
+layout (location=0) in vec3 position;
+
+layout (location=1) in vec4 vColor;
+
+layout (location=2) in vec3 vNormal;
+
+// per instance, see `src/renderer.rs`
+layout (location=3) in mat4x4 instanceModel;
+
+layout (location=7) in vec4 instanceColor;
+
+layout (location=1) out vec4 vertexColor;
+
+layout (location=2) out vec3 vertexNormal;
+
+layout (location=3) out vec3 vertexPosition;
+
+
+void main()
+{
+    vertexColor = vColor * instanceColor;
+    vertexNormal = mat3(instanceModel) * vNormal;
+    vertexPosition = (instanceModel * vec4(position, 1.0f)).xyz;
+}
@@ -0,0 +1,437 @@
+extern crate nalgebra_glm as glm;
+
+use crate::scene_graph::{euler_to_quat, quat_to_euler, SceneNode};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+
+// Keyframe animation of scene nodes.
+//
+// A `Track` animates one property of a node (position, rotation or scale) through a list of
+// keyframes. A `Clip` is a named set of tracks that play together, and knows how to loop. An
+// `Animator` plays clips on one node: it moves the playhead at some speed, which may be negative
+// to play backwards, and can cross-fade from one clip to the next.
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Property {
+    Position,
+    Rotation, // the angles of `SceneNode::rotation`
+    Scale,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    Step,   // hold each keyframe until the next one
+    Linear,
+    Cubic,  // Catmull-Rom through the keyframes, smooth at every key
+    Slerp,  // spherical, along the shortest arc between rotations. Linear for other properties.
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LoopMode {
+    Once,     // stop at either end
+    Loop,     // jump back to the start
+    PingPong, // play forwards, then backwards, then forwards...
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time  : f32, // seconds from the start of the clip
+    pub value : glm::Vec3,
+}
+
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub property      : Property,
+    pub interpolation : Interpolation,
+    pub keyframes     : Vec<Keyframe>, // sorted by time
+}
+
+impl Track {
+    pub fn new(property: Property, interpolation: Interpolation) -> Self {
+        Track { property, interpolation, keyframes: vec![] }
+    }
+
+    // Add a keyframe, keeping them sorted
+    pub fn with_key(mut self, time: f32, value: glm::Vec3) -> Self {
+        let index = self.keyframes.partition_point(|key| key.time <= time);
+        self.keyframes.insert(index, Keyframe { time, value });
+        self
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |key| key.time)
+    }
+
+    // The value at `time`, holding the first and last keyframes outside of the track
+    pub fn sample(&self, time: f32) -> Option<glm::Vec3> {
+        let keys = &self.keyframes;
+        let first = keys.first()?;
+        let last = keys.last()?;
+        if time <= first.time { return Some(first.value) }
+        if time >= last.time { return Some(last.value) }
+
+        // the keyframes either side of `time`
+        let next = keys.partition_point(|key| key.time <= time);
+        let (a, b) = (&keys[next - 1], &keys[next]);
+        let span = b.time - a.time;
+        let t = if span > 0.0 { (time - a.time) / span } else { 1.0 };
+
+        Some(match (self.interpolation, self.property) {
+            (Interpolation::Step, _) => a.value,
+            (Interpolation::Slerp, Property::Rotation) => {
+                let rotation = glm::quat_slerp(&euler_to_quat(&a.value), &euler_to_quat(&b.value), t);
+                quat_to_euler(&rotation)
+            }
+            (Interpolation::Linear, _) | (Interpolation::Slerp, _) => glm::lerp(&a.value, &b.value, t),
+            (Interpolation::Cubic, _) => {
+                // tangents from the neighbouring keyframes, scaled to this segment's length in time
+                let tangent = |i: usize| {
+                    let before = &keys[i.saturating_sub(1)];
+                    let after = &keys[(i + 1).min(keys.len() - 1)];
+                    let width = after.time - before.time;
+                    if width > 0.0 { (after.value - before.value) * (span / width) } else { glm::zero() }
+                };
+                let (m0, m1) = (tangent(next - 1), tangent(next));
+                let (t2, t3) = (t * t, t * t * t);
+                a.value * (2.0 * t3 - 3.0 * t2 + 1.0)
+                    + m0 * (t3 - 2.0 * t2 + t)
+                    + b.value * (-2.0 * t3 + 3.0 * t2)
+                    + m1 * (t3 - t2)
+            }
+        })
+    }
+}
+
+// The properties a clip sets on a node. Those it has no track for are left alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sample {
+    pub position : Option<glm::Vec3>,
+    pub rotation : Option<glm::Vec3>,
+    pub scale    : Option<glm::Vec3>,
+}
+
+impl Sample {
+    // Mix towards `other` by `weight` in [0, 1]. Rotations are blended as quaternions.
+    pub fn blend(&self, other: &Sample, weight: f32) -> Sample {
+        let mix = |a: Option<glm::Vec3>, b: Option<glm::Vec3>, rotation: bool| match (a, b) {
+            (Some(a), Some(b)) if rotation => {
+                Some(quat_to_euler(&glm::quat_slerp(&euler_to_quat(&a), &euler_to_quat(&b), weight)))
+            }
+            (Some(a), Some(b)) => Some(glm::lerp(&a, &b, weight)),
+            (a, b) => a.or(b),
+        };
+        Sample {
+            position : mix(self.position, other.position, false),
+            rotation : mix(self.rotation, other.rotation, true),
+            scale    : mix(self.scale, other.scale, false),
+        }
+    }
+
+    pub fn apply(&self, node: &mut SceneNode) {
+        if let Some(position) = self.position { node.position = position; }
+        if let Some(rotation) = self.rotation { node.rotation = rotation; }
+        if let Some(scale)    = self.scale    { node.scale    = scale; }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Clip {
+    pub name      : String,
+    pub loop_mode : LoopMode,
+    pub tracks    : Vec<Track>,
+}
+
+impl Clip {
+    pub fn new(name: &str, loop_mode: LoopMode) -> Self {
+        Clip { name: name.to_string(), loop_mode, tracks: vec![] }
+    }
+
+    pub fn with_track(mut self, track: Track) -> Self {
+        self.tracks.push(track);
+        self
+    }
+
+    // The length of the longest track
+    pub fn duration(&self) -> f32 {
+        self.tracks.iter().map(|track| track.duration()).fold(0.0, f32::max)
+    }
+
+    // Map time since the clip started onto its tracks, according to the loop mode
+    pub fn local_time(&self, time: f32) -> f32 {
+        let duration = self.duration();
+        if duration <= 0.0 { return 0.0 }
+        match self.loop_mode {
+            LoopMode::Once => time.clamp(0.0, duration),
+            LoopMode::Loop => time.rem_euclid(duration),
+            LoopMode::PingPong => {
+                let t = time.rem_euclid(2.0 * duration);
+                if t > duration { 2.0 * duration - t } else { t }
+            }
+        }
+    }
+
+    pub fn sample(&self, time: f32) -> Sample {
+        let time = self.local_time(time);
+        let mut sample = Sample::default();
+        for track in &self.tracks {
+            let value = track.sample(time);
+            match track.property {
+                Property::Position => sample.position = value.or(sample.position),
+                Property::Rotation => sample.rotation = value.or(sample.rotation),
+                Property::Scale    => sample.scale    = value.or(sample.scale),
+            }
+        }
+        sample
+    }
+}
+
+// One clip being played
+#[derive(Clone, Debug)]
+pub struct Playback {
+    pub clip  : Rc<Clip>,
+    pub time  : f32, // seconds into the clip, before looping
+    pub speed : f32, // 1 plays at normal speed, negative plays backwards
+}
+
+impl Playback {
+    pub fn new(clip: Rc<Clip>) -> Self {
+        Playback { clip, time: 0.0, speed: 1.0 }
+    }
+
+    pub fn update(&mut self, delta_time: f32) {
+        self.time += delta_time * self.speed;
+        // a clip that plays once stays put at its ends, so reversing it starts moving right away
+        if self.clip.loop_mode == LoopMode::Once {
+            self.time = self.time.clamp(0.0, self.clip.duration());
+        }
+    }
+
+    // Whether a clip that plays once has reached the end it is heading for
+    pub fn is_finished(&self) -> bool {
+        self.clip.loop_mode == LoopMode::Once
+            && ((self.speed > 0.0 && self.time >= self.clip.duration()) || (self.speed < 0.0 && self.time <= 0.0))
+    }
+
+    pub fn sample(&self) -> Sample {
+        self.clip.sample(self.time)
+    }
+}
+
+// Plays clips on a node, cross-fading between them
+pub struct Animator {
+    pub current   : Option<Playback>,
+    pub previous  : Option<Playback>, // the clip we are fading out of
+    blend_time    : f32,               // seconds the fade takes
+    blend_elapsed : f32,
+}
+
+impl Animator {
+    pub fn new() -> Self {
+        Animator { current: None, previous: None, blend_time: 0.0, blend_elapsed: 0.0 }
+    }
+
+    // Switch to `clip` straight away, from its start
+    pub fn play(&mut self, clip: Rc<Clip>) {
+        self.current = Some(Playback::new(clip));
+        self.previous = None;
+    }
+
+    // Start `clip` from its start, fading it in over `duration` seconds while the current clip fades out
+    pub fn cross_fade(&mut self, clip: Rc<Clip>, duration: f32) {
+        if duration <= 0.0 || self.current.is_none() {
+            return self.play(clip);
+        }
+        self.previous = self.current.take();
+        self.current = Some(Playback::new(clip));
+        self.blend_time = duration;
+        self.blend_elapsed = 0.0;
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        if let Some(playback) = self.current.as_mut() {
+            playback.speed = speed;
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.current.as_ref().map_or(false, |playback| playback.speed != 0.0 && !playback.is_finished())
+    }
+
+    pub fn update(&mut self, delta_time: f32) {
+        if let Some(playback) = self.current.as_mut() {
+            playback.update(delta_time);
+        }
+        if let Some(playback) = self.previous.as_mut() {
+            playback.update(delta_time);
+            self.blend_elapsed += delta_time;
+            if self.blend_elapsed >= self.blend_time {
+                self.previous = None;
+            }
+        }
+    }
+
+    pub fn sample(&self) -> Sample {
+        let current = self.current.as_ref().map(|playback| playback.sample()).unwrap_or_default();
+        match self.previous.as_ref() {
+            Some(previous) => previous.sample().blend(&current, self.blend_elapsed / self.blend_time),
+            None => current,
+        }
+    }
+
+    pub fn apply(&self, node: &mut SceneNode) {
+        self.sample().apply(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn assert_near(a: glm::Vec3, b: glm::Vec3) {
+        assert!(glm::distance(&a, &b) < 1e-4, "{:?} is not {:?}", a, b);
+    }
+
+    fn x(value: f32) -> glm::Vec3 {
+        glm::vec3(value, 0.0, 0.0)
+    }
+
+    // Position keys 0 at 0s, 1 at 1s and 3 at 2s
+    fn track(interpolation: Interpolation) -> Track {
+        Track::new(Property::Position, interpolation)
+            .with_key(2.0, x(3.0))
+            .with_key(0.0, x(0.0))
+            .with_key(1.0, x(1.0))
+    }
+
+    fn clip(loop_mode: LoopMode, values: (f32, f32)) -> Rc<Clip> {
+        let track = Track::new(Property::Position, Interpolation::Linear)
+            .with_key(0.0, x(values.0))
+            .with_key(2.0, x(values.1));
+        Rc::new(Clip::new("test", loop_mode).with_track(track))
+    }
+
+    #[test]
+    fn tracks_hold_their_ends_and_hit_their_keys() {
+        for interpolation in [Interpolation::Step, Interpolation::Linear, Interpolation::Cubic, Interpolation::Slerp] {
+            let track = track(interpolation);
+            assert_eq!(track.duration(), 2.0);
+            assert_near(track.sample(-1.0).unwrap(), x(0.0));
+            assert_near(track.sample(1.0).unwrap(), x(1.0));
+            assert_near(track.sample(5.0).unwrap(), x(3.0));
+        }
+        assert_eq!(Track::new(Property::Scale, Interpolation::Linear).sample(0.0), None);
+    }
+
+    #[test]
+    fn tracks_interpolate_between_keys() {
+        assert_near(track(Interpolation::Step).sample(1.9).unwrap(), x(1.0));
+        assert_near(track(Interpolation::Linear).sample(1.5).unwrap(), x(2.0));
+        // slerp is linear for anything but rotations
+        assert_near(track(Interpolation::Slerp).sample(0.25).unwrap(), x(0.25));
+
+        // Catmull-Rom follows evenly spaced keys on a line exactly, and curves through bends
+        let line = Track::new(Property::Position, Interpolation::Cubic)
+            .with_key(0.0, x(0.0)).with_key(1.0, x(1.0)).with_key(2.0, x(2.0));
+        assert_near(line.sample(0.5).unwrap(), x(0.5));
+        assert_near(line.sample(1.25).unwrap(), x(1.25));
+        // from 1 to 3 with tangents (3 - 0) / 2 and (3 - 1) / 1, so not on the straight line's 2
+        assert_near(track(Interpolation::Cubic).sample(1.5).unwrap(), x(1.9375));
+    }
+
+    #[test]
+    fn rotations_slerp_the_short_way_round() {
+        let track = Track::new(Property::Rotation, Interpolation::Slerp)
+            .with_key(0.0, glm::vec3(0.0, 0.0, -3.0))
+            .with_key(1.0, glm::vec3(0.0, 0.0, 3.0));
+        // halfway is facing backwards, not back at the start
+        let halfway = track.sample(0.5).unwrap();
+        assert!((halfway.z.abs() - PI).abs() < 1e-3, "{:?}", halfway);
+
+        let quarter = Track::new(Property::Rotation, Interpolation::Slerp)
+            .with_key(0.0, glm::vec3(0.0, 0.0, 0.0))
+            .with_key(1.0, glm::vec3(0.0, 1.0, 0.0))
+            .sample(0.25)
+            .unwrap();
+        assert_near(quarter, glm::vec3(0.0, 0.25, 0.0));
+    }
+
+    #[test]
+    fn clips_map_time_by_loop_mode() {
+        let once = clip(LoopMode::Once, (0.0, 1.0));
+        assert_eq!(once.local_time(-1.0), 0.0);
+        assert_eq!(once.local_time(1.5), 1.5);
+        assert_eq!(once.local_time(7.0), 2.0);
+
+        let looping = clip(LoopMode::Loop, (0.0, 1.0));
+        assert_eq!(looping.local_time(2.5), 0.5);
+        assert_eq!(looping.local_time(-0.5), 1.5);
+
+        let ping_pong = clip(LoopMode::PingPong, (0.0, 1.0));
+        assert_eq!(ping_pong.local_time(1.5), 1.5);
+        assert_eq!(ping_pong.local_time(2.5), 1.5);
+        assert_eq!(ping_pong.local_time(4.5), 0.5);
+
+        let empty = Clip::new("empty", LoopMode::Loop);
+        assert_eq!(empty.local_time(3.0), 0.0);
+        assert_eq!(empty.sample(3.0), Sample::default());
+    }
+
+    #[test]
+    fn playback_stops_at_the_ends_of_clips_played_once() {
+        let mut playback = Playback::new(clip(LoopMode::Once, (0.0, 1.0)));
+        playback.update(1.0);
+        assert!(!playback.is_finished());
+        playback.update(5.0);
+        assert_eq!(playback.time, 2.0);
+        assert!(playback.is_finished());
+
+        // reversed, it starts back straight away and finishes at the start
+        playback.speed = -2.0;
+        assert!(!playback.is_finished());
+        playback.update(0.25);
+        assert_eq!(playback.time, 1.5);
+        playback.update(10.0);
+        assert_eq!(playback.time, 0.0);
+        assert!(playback.is_finished());
+
+        let mut looping = Playback::new(clip(LoopMode::Loop, (0.0, 1.0)));
+        looping.update(5.0);
+        assert_eq!(looping.time, 5.0);
+        assert!(!looping.is_finished());
+        assert_near(looping.sample().position.unwrap(), x(0.5));
+    }
+
+    #[test]
+    fn animators_cross_fade_between_clips() {
+        let mut animator = Animator::new();
+        assert!(!animator.is_playing());
+        assert_eq!(animator.sample(), Sample::default());
+
+        // fading in with nothing playing just plays
+        animator.cross_fade(clip(LoopMode::Once, (0.0, 0.0)), 1.0);
+        assert!(animator.previous.is_none());
+        assert!(animator.is_playing());
+
+        animator.cross_fade(clip(LoopMode::Once, (10.0, 10.0)), 1.0);
+        assert_near(animator.sample().position.unwrap(), x(0.0));
+        animator.update(0.25);
+        assert_near(animator.sample().position.unwrap(), x(2.5));
+        animator.update(0.5);
+        assert_near(animator.sample().position.unwrap(), x(7.5));
+        animator.update(0.5);
+        assert!(animator.previous.is_none());
+        assert_near(animator.sample().position.unwrap(), x(10.0));
+
+        // properties only one of the clips sets are taken from it as they are
+        let scale = Track::new(Property::Scale, Interpolation::Linear).with_key(0.0, glm::vec3(2.0, 2.0, 2.0));
+        animator.cross_fade(Rc::new(Clip::new("scale", LoopMode::Once).with_track(scale)), 1.0);
+        animator.update(0.5);
+        let sample = animator.sample();
+        assert_near(sample.position.unwrap(), x(10.0));
+        assert_near(sample.scale.unwrap(), glm::vec3(2.0, 2.0, 2.0));
+
+        animator.set_speed(0.0);
+        assert!(!animator.is_playing());
+    }
+}
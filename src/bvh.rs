@@ -0,0 +1,625 @@
+extern crate nalgebra_glm as glm;
+
+use crate::mesh::Mesh;
+use crate::scene_graph::SceneNode;
+use std::collections::HashMap;
+
+// Bounding volume hierarchies for ray queries.
+//
+// `Bvh` is built over the triangles of a single `Mesh` and answers closest-hit and any-hit
+// queries in the mesh's own coordinate system. `SceneBvh` is built over the world space bounds
+// of every drawable node in a scene graph, and is used to find which nodes a ray might touch.
+// Both are split using the surface area heuristic (SAH), evaluated over a fixed number of bins.
+
+const SAH_BINS         : usize = 16;
+const MAX_LEAF_SIZE    : usize = 4;
+const TRAVERSAL_COST   : f32   = 1.0;
+const INTERSECTION_COST: f32   = 1.0;
+
+// Axis aligned bounding box
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: glm::Vec3,
+    pub max: glm::Vec3,
+}
+
+impl Aabb {
+    // An inverted box which any point or box will grow
+    pub fn empty() -> Self {
+        Aabb {
+            min: glm::vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: glm::vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[glm::Vec3]) -> Self {
+        let mut bounds = Aabb::empty();
+        for p in points {
+            bounds.grow(p);
+        }
+        bounds
+    }
+
+    pub fn from_mesh(mesh: &Mesh) -> Self {
+        let mut bounds = Aabb::empty();
+        for v in mesh.vertices.chunks_exact(3) {
+            bounds.grow(&glm::vec3(v[0], v[1], v[2]));
+        }
+        bounds
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, p: &glm::Vec3) {
+        self.min = glm::min2(&self.min, p);
+        self.max = glm::max2(&self.max, p);
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: glm::min2(&self.min, &other.min),
+            max: glm::max2(&self.max, &other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> glm::Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extent(&self) -> glm::Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() { return 0.0 }
+        let e = self.extent();
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    // The box enclosing all eight corners of this box after being transformed
+    pub fn transform(&self, matrix: &glm::Mat4) -> Aabb {
+        if self.is_empty() { return *self }
+        let mut bounds = Aabb::empty();
+        for i in 0..8 {
+            let corner = glm::vec3(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            bounds.grow(&(matrix * corner.push(1.0)).xyz());
+        }
+        bounds
+    }
+
+    // Slab test, returns the distance along the ray at which it enters the box
+    pub fn intersect(&self, ray: &Ray, t_max: f32) -> Option<f32> {
+        let t0 = (self.min - ray.origin).component_mul(&ray.inv_direction);
+        let t1 = (self.max - ray.origin).component_mul(&ray.inv_direction);
+        let t_near = glm::min2(&t0, &t1);
+        let t_far  = glm::max2(&t0, &t1);
+        let t_enter = t_near.x.max(t_near.y).max(t_near.z).max(0.0);
+        let t_exit  = t_far.x.min(t_far.y).min(t_far.z).min(t_max);
+        if t_enter <= t_exit { Some(t_enter) } else { None }
+    }
+}
+
+// Ray
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin        : glm::Vec3,
+    pub direction     : glm::Vec3,
+    pub inv_direction : glm::Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: glm::Vec3, direction: glm::Vec3) -> Self {
+        let direction = glm::normalize(&direction);
+        Ray {
+            origin,
+            direction,
+            inv_direction: glm::vec3(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z),
+        }
+    }
+
+    pub fn at(&self, t: f32) -> glm::Vec3 {
+        self.origin + self.direction * t
+    }
+
+    // The same ray expressed in the coordinate system of `matrix`, where `matrix` maps from
+    // that coordinate system into the one the ray currently lives in
+    pub fn to_local(self, matrix: &glm::Mat4) -> Option<Ray> {
+        let inverse = glm::inverse(matrix);
+        let origin    = (inverse * self.origin.push(1.0)).xyz();
+        let direction = (inverse * self.direction.push(0.0)).xyz();
+        if glm::length2(&direction) == 0.0 { return None }
+        Some(Ray::new(origin, direction))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub t        : f32,       // Distance along the ray
+    pub triangle : usize,     // Index of the triangle in the mesh index buffer, divided by three
+    pub u        : f32,       // Barycentric coordinates of the hit within the triangle
+    pub v        : f32,
+    pub normal   : glm::Vec3, // Geometric normal, facing the ray origin
+}
+
+// Möller–Trumbore ray-triangle intersection
+fn intersect_triangle(ray: &Ray, tri: &[glm::Vec3; 3], t_max: f32) -> Option<(f32, f32, f32)> {
+    let edge1 = tri[1] - tri[0];
+    let edge2 = tri[2] - tri[0];
+    let p = ray.direction.cross(&edge2);
+    let det = edge1.dot(&p);
+    if det.abs() < 1e-8 { return None }
+    let inv_det = 1.0 / det;
+    let s = ray.origin - tri[0];
+    let u = s.dot(&p) * inv_det;
+    if !(0.0..=1.0).contains(&u) { return None }
+    let q = s.cross(&edge1);
+    let v = ray.direction.dot(&q) * inv_det;
+    if v < 0.0 || u + v > 1.0 { return None }
+    let t = edge2.dot(&q) * inv_det;
+    if t > 1e-5 && t < t_max { Some((t, u, v)) } else { None }
+}
+
+// Flattened tree node. Leaves hold `count` primitives starting at `first`,
+// interior nodes have `count == 0` and their children at `first` and `first + 1`.
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    bounds : Aabb,
+    first  : usize,
+    count  : usize,
+}
+
+// Builds a flattened tree over primitives described only by their bounds. Returns the nodes,
+// and the primitive indices reordered so every leaf refers to a contiguous range.
+fn build_tree(bounds: &[Aabb]) -> (Vec<BvhNode>, Vec<usize>) {
+    let centroids: Vec<glm::Vec3> = bounds.iter().map(|b| b.centroid()).collect();
+    let mut order: Vec<usize> = (0..bounds.len()).collect();
+    let mut nodes = Vec::with_capacity(bounds.len() * 2);
+    nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: bounds.len() });
+    if !bounds.is_empty() {
+        subdivide(0, &mut nodes, &mut order, bounds, &centroids);
+    }
+    (nodes, order)
+}
+
+fn subdivide(
+    node_index: usize,
+    nodes: &mut Vec<BvhNode>,
+    order: &mut [usize],
+    bounds: &[Aabb],
+    centroids: &[glm::Vec3],
+) {
+    let first = nodes[node_index].first;
+    let count = nodes[node_index].count;
+    let range = &mut order[first..first + count];
+
+    let mut node_bounds = Aabb::empty();
+    let mut centroid_bounds = Aabb::empty();
+    for &i in range.iter() {
+        node_bounds = node_bounds.union(&bounds[i]);
+        centroid_bounds.grow(&centroids[i]);
+    }
+    nodes[node_index].bounds = node_bounds;
+
+    if count <= MAX_LEAF_SIZE { return }
+
+    // Find the cheapest split plane among the bin boundaries of every axis
+    let leaf_cost = INTERSECTION_COST * count as f32;
+    let mut best: Option<(usize, usize, f32)> = None; // (axis, split bin, cost)
+    for (axis, (&lo, &hi)) in centroid_bounds.min.iter().zip(centroid_bounds.max.iter()).enumerate() {
+        if hi - lo <= f32::EPSILON { continue }
+        let scale = SAH_BINS as f32 / (hi - lo);
+
+        let mut bin_bounds = [Aabb::empty(); SAH_BINS];
+        let mut bin_counts = [0usize; SAH_BINS];
+        for &i in range.iter() {
+            let b = (((centroids[i][axis] - lo) * scale) as usize).min(SAH_BINS - 1);
+            bin_bounds[b] = bin_bounds[b].union(&bounds[i]);
+            bin_counts[b] += 1;
+        }
+
+        // Sweep from the right to collect the area and count of every suffix
+        let mut right_area  = [0.0f32; SAH_BINS];
+        let mut right_count = [0usize; SAH_BINS];
+        let mut acc_bounds = Aabb::empty();
+        let mut acc_count  = 0;
+        for b in (1..SAH_BINS).rev() {
+            acc_bounds = acc_bounds.union(&bin_bounds[b]);
+            acc_count += bin_counts[b];
+            right_area[b]  = acc_bounds.surface_area();
+            right_count[b] = acc_count;
+        }
+
+        let mut acc_bounds = Aabb::empty();
+        let mut acc_count  = 0;
+        for b in 1..SAH_BINS {
+            acc_bounds = acc_bounds.union(&bin_bounds[b - 1]);
+            acc_count += bin_counts[b - 1];
+            if acc_count == 0 || right_count[b] == 0 { continue }
+            let cost = TRAVERSAL_COST + INTERSECTION_COST
+                * (acc_bounds.surface_area() * acc_count as f32 + right_area[b] * right_count[b] as f32)
+                / node_bounds.surface_area().max(f32::EPSILON);
+            if best.map_or(true, |(_, _, c)| cost < c) {
+                best = Some((axis, b, cost));
+            }
+        }
+    }
+
+    let (axis, split, cost) = match best {
+        Some(split) => split,
+        None => return, // every centroid coincides, nothing to split on
+    };
+    if cost >= leaf_cost { return }
+
+    // Partition the range around the chosen bin boundary
+    let lo = centroid_bounds.min[axis];
+    let scale = SAH_BINS as f32 / (centroid_bounds.max[axis] - lo);
+    let mut left_count = 0;
+    for j in 0..count {
+        let i = range[j];
+        let b = (((centroids[i][axis] - lo) * scale) as usize).min(SAH_BINS - 1);
+        if b < split {
+            range.swap(j, left_count);
+            left_count += 1;
+        }
+    }
+
+    let left_index = nodes.len();
+    nodes.push(BvhNode { bounds: Aabb::empty(), first,              count: left_count });
+    nodes.push(BvhNode { bounds: Aabb::empty(), first: first + left_count, count: count - left_count });
+    nodes[node_index].first = left_index;
+    nodes[node_index].count = 0;
+
+    subdivide(left_index,     nodes, order, bounds, centroids);
+    subdivide(left_index + 1, nodes, order, bounds, centroids);
+}
+
+// Walks the tree front to back, calling `visit` for every primitive in a leaf the ray reaches.
+// `visit` returns the new maximum distance, which prunes subtrees lying further away.
+fn traverse<F>(nodes: &[BvhNode], order: &[usize], ray: &Ray, mut t_max: f32, mut visit: F)
+where
+    F: FnMut(usize, f32) -> Option<f32>,
+{
+    if nodes.is_empty() || nodes[0].bounds.is_empty() { return }
+    let mut stack: Vec<usize> = Vec::with_capacity(64);
+    if nodes[0].bounds.intersect(ray, t_max).is_some() {
+        stack.push(0);
+    }
+    while let Some(index) = stack.pop() {
+        let node = &nodes[index];
+        if node.count > 0 {
+            for &primitive in &order[node.first..node.first + node.count] {
+                match visit(primitive, t_max) {
+                    Some(t) => t_max = t,
+                    None => return, // the visitor asked us to stop
+                }
+            }
+            continue;
+        }
+
+        let (a, b) = (node.first, node.first + 1);
+        let hit_a = nodes[a].bounds.intersect(ray, t_max);
+        let hit_b = nodes[b].bounds.intersect(ray, t_max);
+        match (hit_a, hit_b) {
+            (Some(ta), Some(tb)) => {
+                // push the far child first, so the near one is popped first
+                if ta <= tb { stack.push(b); stack.push(a); } else { stack.push(a); stack.push(b); }
+            }
+            (Some(_), None) => stack.push(a),
+            (None, Some(_)) => stack.push(b),
+            (None, None) => {}
+        }
+    }
+}
+
+// Triangle BVH over a single mesh
+
+pub struct Bvh {
+    nodes     : Vec<BvhNode>,
+    order     : Vec<usize>,
+    triangles : Vec<[glm::Vec3; 3]>,
+}
+
+impl Bvh {
+    pub fn build(mesh: &Mesh) -> Self {
+        let vertex = |i: u32| {
+            let i = i as usize * 3;
+            glm::vec3(mesh.vertices[i], mesh.vertices[i + 1], mesh.vertices[i + 2])
+        };
+        let triangles: Vec<[glm::Vec3; 3]> = mesh.indices
+            .chunks_exact(3)
+            .map(|t| [vertex(t[0]), vertex(t[1]), vertex(t[2])])
+            .collect();
+        let bounds: Vec<Aabb> = triangles.iter().map(|t| Aabb::from_points(t)).collect();
+        let (nodes, order) = build_tree(&bounds);
+        Bvh { nodes, order, triangles }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn closest_hit(&self, ray: &Ray, t_max: f32) -> Option<Hit> {
+        let mut closest: Option<Hit> = None;
+        traverse(&self.nodes, &self.order, ray, t_max, |i, t_max| {
+            if let Some((t, u, v)) = intersect_triangle(ray, &self.triangles[i], t_max) {
+                let tri = &self.triangles[i];
+                let mut normal = glm::normalize(&(tri[1] - tri[0]).cross(&(tri[2] - tri[0])));
+                if normal.dot(&ray.direction) > 0.0 {
+                    normal = -normal;
+                }
+                closest = Some(Hit { t, triangle: i, u, v, normal });
+                return Some(t);
+            }
+            Some(t_max)
+        });
+        closest
+    }
+
+    // Cheaper than `closest_hit` as it stops at the first triangle found, used for occlusion
+    pub fn any_hit(&self, ray: &Ray, t_max: f32) -> bool {
+        let mut found = false;
+        traverse(&self.nodes, &self.order, ray, t_max, |i, t_max| {
+            if intersect_triangle(ray, &self.triangles[i], t_max).is_some() {
+                found = true;
+                return None;
+            }
+            Some(t_max)
+        });
+        found
+    }
+
+    // Whether the straight line between two points is unobstructed by the mesh
+    pub fn line_of_sight(&self, from: &glm::Vec3, to: &glm::Vec3) -> bool {
+        let distance = glm::distance(from, to);
+        if distance <= f32::EPSILON { return true }
+        !self.any_hit(&Ray::new(*from, to - from), distance)
+    }
+
+    // Height of the highest surface below or above the point (x, z), for following the terrain
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let bounds = self.bounds();
+        if bounds.is_empty() { return None }
+        let top = bounds.max.y + 1.0;
+        let ray = Ray::new(glm::vec3(x, top, z), glm::vec3(0.0, -1.0, 0.0));
+        self.closest_hit(&ray, top - bounds.min.y + 1.0).map(|hit| ray.at(hit.t).y)
+    }
+}
+
+// Scene level BVH over the world space bounds of the drawable nodes of a scene graph
+
+#[derive(Clone, Copy, Debug)]
+pub struct SceneHit {
+    pub node : *mut SceneNode,
+    pub t    : f32,            // Distance along the ray at which it enters the node bounds
+}
+
+pub struct SceneBvh {
+    nodes  : Vec<BvhNode>,
+    order  : Vec<usize>,
+    items  : Vec<(*mut SceneNode, Aabb)>,
+}
+
+impl SceneBvh {
+    // `mesh_bounds` maps the VAO of every drawable node to the bounds of its mesh in model space.
+    // The BVH is a snapshot; rebuild it after moving nodes around.
+    pub fn build(root: &SceneNode, mesh_bounds: &HashMap<u32, Aabb>) -> Self {
+        let mut items = vec![];
+        collect_world_bounds(root, &root.local_transform(), mesh_bounds, &mut items);
+        let bounds: Vec<Aabb> = items.iter().map(|(_, b)| *b).collect();
+        let (nodes, order) = build_tree(&bounds);
+        SceneBvh { nodes, order, items }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+
+    // All nodes whose bounds the ray passes through, nearest first
+    pub fn intersect(&self, ray: &Ray, t_max: f32) -> Vec<SceneHit> {
+        let mut hits = vec![];
+        traverse(&self.nodes, &self.order, ray, t_max, |i, t_max| {
+            let (node, bounds) = self.items[i];
+            if let Some(t) = bounds.intersect(ray, t_max) {
+                hits.push(SceneHit { node, t });
+            }
+            Some(t_max)
+        });
+        hits.sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap());
+        hits
+    }
+
+    // The nearest node whose bounds the ray passes through
+    pub fn closest_hit(&self, ray: &Ray, t_max: f32) -> Option<SceneHit> {
+        let mut closest: Option<SceneHit> = None;
+        traverse(&self.nodes, &self.order, ray, t_max, |i, t_max| {
+            let (node, bounds) = self.items[i];
+            match bounds.intersect(ray, t_max) {
+                Some(t) if closest.map_or(true, |c| t < c.t) => {
+                    closest = Some(SceneHit { node, t });
+                    Some(t_max)
+                }
+                _ => Some(t_max),
+            }
+        });
+        closest
+    }
+
+    pub fn any_hit(&self, ray: &Ray, t_max: f32) -> bool {
+        let mut found = false;
+        traverse(&self.nodes, &self.order, ray, t_max, |i, t_max| {
+            if self.items[i].1.intersect(ray, t_max).is_some() {
+                found = true;
+                return None;
+            }
+            Some(t_max)
+        });
+        found
+    }
+}
+
+// World space bounds of everything drawn by `node` and its children, given the full
+// transformation of `node` (see `SceneNode::transform_of`)
+pub fn subtree_bounds(node: &SceneNode, node_transform: &glm::Mat4, mesh_bounds: &HashMap<u32, Aabb>) -> Aabb {
+    let mut items = vec![];
+    collect_world_bounds(node, node_transform, mesh_bounds, &mut items);
+    items.iter().fold(Aabb::empty(), |acc, (_, b)| acc.union(b))
+}
+
+fn collect_world_bounds(
+    node: &SceneNode,
+    node_transform: &glm::Mat4,
+    mesh_bounds: &HashMap<u32, Aabb>,
+    items: &mut Vec<(*mut SceneNode, Aabb)>,
+) {
+    if node.index_count > 0 {
+        if let Some(bounds) = mesh_bounds.get(&node.vao_id) {
+            items.push((node as *const SceneNode as *mut SceneNode, bounds.transform(node_transform)));
+        }
+    }
+    for &child in &node.children {
+        let child = unsafe { &*child };
+        collect_world_bounds(child, &(node_transform * child.local_transform()), mesh_bounds, items);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn mesh(triangles: &[[glm::Vec3; 3]]) -> Mesh {
+        let vertices: Vec<f32> = triangles.iter().flatten().flat_map(|v| [v.x, v.y, v.z]).collect();
+        let indices: Vec<u32> = (0..triangles.len() as u32 * 3).collect();
+        Mesh { vertices, normals: vec![], colors: vec![], index_count: indices.len() as i32, indices }
+    }
+
+    fn random_point(rng: &mut StdRng, size: f32) -> glm::Vec3 {
+        glm::vec3(rng.gen_range(-size..size), rng.gen_range(-size..size), rng.gen_range(-size..size))
+    }
+
+    // Small triangles scattered through a cube, many overlapping
+    fn random_triangles(rng: &mut StdRng, count: usize) -> Vec<[glm::Vec3; 3]> {
+        (0..count).map(|_| {
+            let corner = random_point(rng, 10.0);
+            [corner, corner + random_point(rng, 1.5), corner + random_point(rng, 1.5)]
+        }).collect()
+    }
+
+    fn random_ray(rng: &mut StdRng) -> Ray {
+        loop {
+            let direction = random_point(rng, 1.0);
+            if glm::length(&direction) > 0.1 {
+                return Ray::new(random_point(rng, 14.0), direction);
+            }
+        }
+    }
+
+    // The closest hit, trying every triangle
+    fn brute_force(triangles: &[[glm::Vec3; 3]], ray: &Ray, t_max: f32) -> Option<(usize, f32)> {
+        let mut closest = None;
+        for (i, triangle) in triangles.iter().enumerate() {
+            if let Some((t, _, _)) = intersect_triangle(ray, triangle, t_max) {
+                if closest.map_or(true, |(_, closest_t)| t < closest_t) {
+                    closest = Some((i, t));
+                }
+            }
+        }
+        closest
+    }
+
+    #[test]
+    fn hits_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(26);
+        let triangles = random_triangles(&mut rng, 2000);
+        let bvh = Bvh::build(&mesh(&triangles));
+        assert_eq!(bvh.triangle_count(), triangles.len());
+
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let ray = random_ray(&mut rng);
+            let t_max = if rng.gen_bool(0.5) { f32::INFINITY } else { rng.gen_range(1.0..30.0) };
+            let expected = brute_force(&triangles, &ray, t_max);
+            let hit = bvh.closest_hit(&ray, t_max);
+            match (expected, hit) {
+                (None, None) => {}
+                (Some((triangle, t)), Some(hit)) => {
+                    hits += 1;
+                    assert!((hit.t - t).abs() < 1e-4, "hit at {} rather than {}", hit.t, t);
+                    // two triangles may be hit at the same distance, either will do
+                    assert!(hit.triangle == triangle || (hit.t - t).abs() < 1e-6);
+                    assert!(hit.normal.dot(&ray.direction) <= 0.0);
+                }
+                (expected, hit) => panic!("expected {:?}, found {:?}", expected, hit.map(|hit| (hit.triangle, hit.t))),
+            }
+            assert_eq!(bvh.any_hit(&ray, t_max), expected.is_some());
+        }
+        // the rays should test something
+        assert!(hits > 50, "only {} rays hit", hits);
+    }
+
+    #[test]
+    fn height_is_the_highest_surface() {
+        let square = |y: f32| [
+            [glm::vec3(0.0, y, 0.0), glm::vec3(0.0, y, 4.0), glm::vec3(4.0, y, 0.0)],
+            [glm::vec3(4.0, y, 0.0), glm::vec3(0.0, y, 4.0), glm::vec3(4.0, y, 4.0)],
+        ];
+        let mut triangles = square(1.0).to_vec();
+        // a slope over part of it, rising along x from 2 to 3
+        triangles.push([glm::vec3(2.0, 2.0, 0.0), glm::vec3(2.0, 2.0, 2.0), glm::vec3(4.0, 3.0, 0.0)]);
+        let bvh = Bvh::build(&mesh(&triangles));
+
+        assert!((bvh.height_at(1.0, 1.0).unwrap() - 1.0).abs() < 1e-5);
+        assert!((bvh.height_at(3.0, 0.5).unwrap() - 2.5).abs() < 1e-5);
+        assert!((bvh.height_at(3.0, 3.5).unwrap() - 1.0).abs() < 1e-5);
+        assert_eq!(bvh.height_at(5.0, 1.0), None);
+        assert_eq!(bvh.height_at(-0.1, 1.0), None);
+    }
+
+    #[test]
+    fn empty_mesh_has_no_hits() {
+        let bvh = Bvh::build(&mesh(&[]));
+        let ray = Ray::new(glm::vec3(0.0, 0.0, 0.0), glm::vec3(1.0, 0.0, 0.0));
+        assert_eq!(bvh.triangle_count(), 0);
+        assert!(bvh.bounds().is_empty());
+        assert!(bvh.closest_hit(&ray, f32::INFINITY).is_none());
+        assert!(!bvh.any_hit(&ray, f32::INFINITY));
+        assert!(bvh.line_of_sight(&glm::vec3(0.0, 0.0, 0.0), &glm::vec3(5.0, 0.0, 0.0)));
+        assert_eq!(bvh.height_at(0.0, 0.0), None);
+    }
+
+    #[test]
+    fn degenerate_triangles_are_never_hit() {
+        let point = glm::vec3(1.0, 1.0, 1.0);
+        let mut triangles = vec![
+            [point, point, point],
+            [glm::vec3(0.0, 0.0, 0.0), glm::vec3(1.0, 0.0, 0.0), glm::vec3(2.0, 0.0, 0.0)], // a line
+        ];
+        let bvh = Bvh::build(&mesh(&triangles));
+        let down = Ray::new(glm::vec3(1.0, 5.0, 0.0), glm::vec3(0.0, -1.0, 0.0));
+        assert!(bvh.closest_hit(&down, f32::INFINITY).is_none());
+        assert!(!bvh.any_hit(&down, f32::INFINITY));
+        assert_eq!(bvh.height_at(1.0, 0.0), None);
+
+        // among proper ones, and many copies of one triangle, whose centroids can't be split
+        let flat = [glm::vec3(0.0, 0.0, -1.0), glm::vec3(0.0, 0.0, 1.0), glm::vec3(2.0, 0.0, 0.0)];
+        triangles.extend(std::iter::repeat(flat).take(50));
+        let bvh = Bvh::build(&mesh(&triangles));
+        let hit = bvh.closest_hit(&down, f32::INFINITY).expect("the flat triangles are below");
+        assert!(hit.triangle >= 2);
+        assert!((hit.t - 5.0).abs() < 1e-5);
+        assert_eq!(bvh.height_at(1.0, 0.0), Some(0.0));
+    }
+}
@@ -0,0 +1,150 @@
+extern crate nalgebra_glm as glm;
+
+// Cameras: where we look from (`Pose`) and how the view volume is projected onto the screen.
+// Angles are measured in radians. A yaw of zero looks down the negative Z axis, positive yaw
+// turns to the right and positive pitch looks up.
+
+pub const MAX_PITCH: f32 = 89.0 * std::f32::consts::PI / 180.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Pose {
+    pub position : glm::Vec3,
+    pub yaw      : f32,
+    pub pitch    : f32,
+}
+
+impl Pose {
+    pub fn new(position: glm::Vec3) -> Self {
+        Pose { position, yaw: 0.0, pitch: 0.0 }
+    }
+
+    pub fn forward(&self) -> glm::Vec3 {
+        glm::vec3(
+            self.yaw.sin() * self.pitch.cos(),
+            self.pitch.sin(),
+            -self.yaw.cos() * self.pitch.cos(),
+        )
+    }
+
+    // Horizontal, so strafing never changes height
+    pub fn right(&self) -> glm::Vec3 {
+        glm::vec3(self.yaw.cos(), 0.0, self.yaw.sin())
+    }
+
+    pub fn up(&self) -> glm::Vec3 {
+        self.right().cross(&self.forward())
+    }
+
+    // Keeps the pitch away from straight up or down, where the view matrix degenerates
+    pub fn clamp_pitch(&mut self) {
+        self.pitch = self.pitch.clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    // Turn to face a point in world space
+    pub fn look_at(&mut self, target: &glm::Vec3) {
+        let dir = target - self.position;
+        if glm::length2(&dir) <= f32::EPSILON { return }
+        let dir = glm::normalize(&dir);
+        self.yaw   = dir.x.atan2(-dir.z);
+        self.pitch = dir.y.asin();
+        self.clamp_pitch();
+    }
+
+    pub fn view(&self) -> glm::Mat4 {
+        glm::look_at(&self.position, &(self.position + self.forward()), &glm::vec3(0.0, 1.0, 0.0))
+    }
+}
+
+pub trait Camera {
+    fn pose(&self) -> &Pose;
+    fn pose_mut(&mut self) -> &mut Pose;
+    fn projection(&self) -> glm::Mat4;
+    fn set_aspect_ratio(&mut self, aspect_ratio: f32);
+
+    fn view(&self) -> glm::Mat4 {
+        self.pose().view()
+    }
+
+    fn view_projection(&self) -> glm::Mat4 {
+        self.projection() * self.view()
+    }
+
+    // Call whenever the window size changes
+    fn resize(&mut self, width: u32, height: u32) {
+        if width > 0 && height > 0 {
+            self.set_aspect_ratio(width as f32 / height as f32);
+        }
+    }
+}
+
+// Perspective
+
+pub struct PerspectiveCamera {
+    pub pose         : Pose,
+    pub fov_y        : f32, // vertical field of view
+    pub aspect_ratio : f32,
+    pub near         : f32,
+    pub far          : f32,
+}
+
+impl PerspectiveCamera {
+    pub fn new(aspect_ratio: f32) -> Self {
+        PerspectiveCamera {
+            pose         : Pose::new(glm::zero()),
+            fov_y        : 60.0f32.to_radians(),
+            aspect_ratio,
+            near         : 1.0,
+            far          : 1000.0,
+        }
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn pose(&self) -> &Pose { &self.pose }
+    fn pose_mut(&mut self) -> &mut Pose { &mut self.pose }
+
+    fn projection(&self) -> glm::Mat4 {
+        glm::perspective(self.aspect_ratio, self.fov_y, self.near, self.far)
+    }
+
+    fn set_aspect_ratio(&mut self, aspect_ratio: f32) {
+        self.aspect_ratio = aspect_ratio;
+    }
+}
+
+// Orthographic
+
+pub struct OrthographicCamera {
+    pub pose         : Pose,
+    pub height       : f32, // height of the view volume in world units, the width follows the aspect ratio
+    pub aspect_ratio : f32,
+    pub near         : f32,
+    pub far          : f32,
+}
+
+impl OrthographicCamera {
+    pub fn new(aspect_ratio: f32, height: f32) -> Self {
+        OrthographicCamera {
+            pose         : Pose::new(glm::zero()),
+            height,
+            aspect_ratio,
+            near         : 1.0,
+            far          : 1000.0,
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn pose(&self) -> &Pose { &self.pose }
+    fn pose_mut(&mut self) -> &mut Pose { &mut self.pose }
+
+    fn projection(&self) -> glm::Mat4 {
+        let half_h = self.height * 0.5;
+        let half_w = half_h * self.aspect_ratio;
+        glm::ortho(-half_w, half_w, -half_h, half_h, self.near, self.far)
+    }
+
+    fn set_aspect_ratio(&mut self, aspect_ratio: f32) {
+        self.aspect_ratio = aspect_ratio;
+    }
+}
@@ -0,0 +1,212 @@
+extern crate nalgebra_glm as glm;
+
+use crate::bvh::Aabb;
+use crate::camera::{Pose, MAX_PITCH};
+
+// Controllers that move a camera `Pose` around in response to input. They don't know about
+// keys or mice, the caller turns whatever input it has into movement axes and look deltas.
+
+// Free flying first person camera
+
+pub struct FlyController {
+    pub move_speed        : f32, // world units per second
+    pub mouse_sensitivity : f32, // radians per pixel of mouse movement
+    pub turn_speed        : f32, // radians per second, for looking around with the keyboard
+}
+
+impl FlyController {
+    pub fn new() -> Self {
+        FlyController {
+            move_speed        : 20.0,
+            mouse_sensitivity : 0.002,
+            turn_speed        : 60.0f32.to_radians(),
+        }
+    }
+
+    // Mouse look, `delta` is the mouse movement in pixels since the last frame
+    pub fn look(&self, pose: &mut Pose, delta: (f32, f32)) {
+        pose.yaw   += delta.0 * self.mouse_sensitivity;
+        pose.pitch -= delta.1 * self.mouse_sensitivity;
+        pose.clamp_pitch();
+    }
+
+    // Keyboard look, both axes in [-1, 1]
+    pub fn turn(&self, pose: &mut Pose, yaw: f32, pitch: f32, delta_time: f32) {
+        pose.yaw   += yaw   * self.turn_speed * delta_time;
+        pose.pitch += pitch * self.turn_speed * delta_time;
+        pose.clamp_pitch();
+    }
+
+    // `movement` holds the right, up and forward axes in [-1, 1]. Forward follows the view
+    // direction, including pitch, while up is always the world up.
+    pub fn translate(&self, pose: &mut Pose, movement: &glm::Vec3, delta_time: f32) {
+        let mut direction = pose.right()   * movement.x
+                          + glm::vec3(0.0, 1.0, 0.0) * movement.y
+                          + pose.forward() * movement.z;
+        // moving diagonally shouldn't be faster
+        if glm::length2(&direction) > 1.0 {
+            direction = glm::normalize(&direction);
+        }
+        pose.position += direction * self.move_speed * delta_time;
+    }
+}
+
+// Orbit camera circling a target point, usually the position of a scene node
+
+pub struct OrbitController {
+    pub target             : glm::Vec3, // the point we orbit, before panning
+    pub pan_offset         : glm::Vec3, // how far panning has moved us away from the target
+    pub distance           : f32,
+    pub yaw                : f32,
+    pub pitch              : f32,
+    pub rotate_sensitivity : f32,       // radians per pixel of mouse movement
+    pub pan_sensitivity    : f32,       // world units per pixel, per unit of distance
+    pub zoom_step          : f32,       // fraction of the distance covered by one scroll line
+    pub min_distance       : f32,
+    pub max_distance       : f32,
+}
+
+impl OrbitController {
+    pub fn new() -> Self {
+        OrbitController {
+            target             : glm::zero(),
+            pan_offset         : glm::zero(),
+            distance           : 30.0,
+            yaw                : 0.0,
+            pitch              : -20.0f32.to_radians(),
+            rotate_sensitivity : 0.005,
+            pan_sensitivity    : 0.0015,
+            zoom_step          : 0.1,
+            min_distance       : 1.0,
+            max_distance       : 500.0,
+        }
+    }
+
+    pub fn focus(&self) -> glm::Vec3 {
+        self.target + self.pan_offset
+    }
+
+    // Start orbiting from wherever the camera currently is
+    pub fn align_to(&mut self, pose: &Pose) {
+        let offset = self.focus() - pose.position;
+        self.distance = glm::length(&offset).clamp(self.min_distance, self.max_distance);
+        let mut aligned = *pose;
+        aligned.look_at(&self.focus());
+        self.yaw   = aligned.yaw;
+        self.pitch = aligned.pitch;
+    }
+
+    // Mouse drag, `delta` in pixels
+    pub fn rotate(&mut self, delta: (f32, f32)) {
+        self.yaw   += delta.0 * self.rotate_sensitivity;
+        self.pitch -= delta.1 * self.rotate_sensitivity;
+        self.pitch = self.pitch.clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    // Scroll wheel, positive `lines` zoom in
+    pub fn zoom(&mut self, lines: f32) {
+        self.distance *= (1.0 - self.zoom_step).powf(lines);
+        self.distance = self.distance.clamp(self.min_distance, self.max_distance);
+    }
+
+    // Mouse drag, moves the focus in the view plane so the scene follows the cursor
+    pub fn pan(&mut self, pose: &Pose, delta: (f32, f32)) {
+        let scale = self.distance * self.pan_sensitivity;
+        self.pan_offset += (-pose.right() * delta.0 + pose.up() * delta.1) * scale;
+    }
+
+    // Centre on `bounds` and back off until a sphere around them fits the vertical field of view
+    pub fn frame(&mut self, bounds: &Aabb, fov_y: f32) {
+        if bounds.is_empty() { return }
+        let radius = glm::length(&bounds.extent()) * 0.5;
+        self.pan_offset = bounds.centroid() - self.target;
+        self.distance = (radius / (fov_y * 0.5).sin()).clamp(self.min_distance, self.max_distance);
+    }
+
+    pub fn apply(&self, pose: &mut Pose) {
+        pose.yaw   = self.yaw;
+        pose.pitch = self.pitch;
+        pose.clamp_pitch();
+        pose.position = self.focus() - pose.forward() * self.distance;
+    }
+}
+
+// Camera following behind a moving node, lagging behind it on a critically damped spring
+
+pub struct ChaseController {
+    pub offset      : glm::Vec3, // where to be relative to the node: right, up and behind its heading
+    pub look_ahead  : f32,       // how far ahead of the node, along its heading, to look
+    pub look_height : f32,       // how far above the node to look
+    pub stiffness   : f32,       // spring constant pulling us into place, zero attaches us rigidly
+
+    position : glm::Vec3,
+    velocity : glm::Vec3,
+    attached : bool,             // false until we've been placed behind a node for the first time
+}
+
+impl ChaseController {
+    pub fn chase() -> Self {
+        ChaseController {
+            offset      : glm::vec3(0.0, 8.0, 28.0),
+            look_ahead  : 15.0,
+            look_height : 2.0,
+            stiffness   : 12.0,
+            position    : glm::zero(),
+            velocity    : glm::zero(),
+            attached    : false,
+        }
+    }
+
+    // Sitting in the cockpit, looking out ahead
+    pub fn cockpit() -> Self {
+        ChaseController {
+            offset      : glm::vec3(0.0, 3.4, -2.5),
+            look_ahead  : 40.0,
+            look_height : 3.0,
+            stiffness   : 0.0,
+            ..ChaseController::chase()
+        }
+    }
+
+    // Jump straight into place on the next update, e.g. after switching to another node
+    pub fn reset(&mut self) {
+        self.attached = false;
+    }
+
+    // `node_transform` is the full transformation of the followed node
+    pub fn update(&mut self, pose: &mut Pose, node_transform: &glm::Mat4, delta_time: f32) {
+        let up = glm::vec3(0.0, 1.0, 0.0);
+        let node_position = (node_transform * glm::vec4(0.0, 0.0, 0.0, 1.0)).xyz();
+        // the helicopter model faces down its negative Z axis, ignore its pitch and roll
+        let mut forward = (node_transform * glm::vec4(0.0, 0.0, -1.0, 0.0)).xyz();
+        forward.y = 0.0;
+        if glm::length2(&forward) <= f32::EPSILON {
+            forward = pose.forward();
+            forward.y = 0.0;
+        }
+        let forward = glm::normalize(&forward);
+        let right = forward.cross(&up);
+
+        let desired = node_position + right * self.offset.x + up * self.offset.y - forward * self.offset.z;
+
+        if !self.attached || self.stiffness <= 0.0 {
+            self.position = desired;
+            self.velocity = glm::zero();
+            self.attached = true;
+        } else {
+            // small fixed steps keep the spring stable through long frames
+            let damping = 2.0 * self.stiffness.sqrt();
+            let mut remaining = delta_time.min(0.25);
+            while remaining > 0.0 {
+                let dt = remaining.min(1.0 / 120.0);
+                let acceleration = (desired - self.position) * self.stiffness - self.velocity * damping;
+                self.velocity += acceleration * dt;
+                self.position += self.velocity * dt;
+                remaining -= dt;
+            }
+        }
+
+        pose.position = self.position;
+        pose.look_at(&(node_position + forward * self.look_ahead + up * self.look_height));
+    }
+}
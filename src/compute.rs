@@ -0,0 +1,46 @@
+use gl::types::GLenum;
+
+// The barriers between compute programs writing memory and anything reading it afterwards.
+//
+// Dispatches, run with `Shader::dispatch` or `Shader::dispatch_items`, write images and buffers
+// without waiting for whatever reads them next, so a `memory_barrier` naming how the memory is
+// read goes in between. See `Heightmap::generate` in `src/terrain.rs`.
+
+// What will read the memory a compute program wrote. The write must be finished before then.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Barrier {
+    Storage,          // other shaders reading storage buffers
+    VertexAttributes, // a draw using the buffer as vertex data, like particle positions
+    Indices,          // a draw using the buffer as indices
+    Commands,         // indirect draws and dispatches
+    Uniforms,         // shaders reading the buffer as a uniform buffer
+    Textures,         // shaders sampling textures written as images
+    Images,           // shaders loading images
+    Download,         // copies back to the CPU, like `glGetBufferSubData`
+    All,
+}
+
+impl From<Barrier> for GLenum {
+    fn from(barrier: Barrier) -> GLenum {
+        match barrier {
+            Barrier::Storage          => gl::SHADER_STORAGE_BARRIER_BIT,
+            Barrier::VertexAttributes => gl::VERTEX_ATTRIB_ARRAY_BARRIER_BIT,
+            Barrier::Indices          => gl::ELEMENT_ARRAY_BARRIER_BIT,
+            Barrier::Commands         => gl::COMMAND_BARRIER_BIT,
+            Barrier::Uniforms         => gl::UNIFORM_BARRIER_BIT,
+            Barrier::Textures         => gl::TEXTURE_FETCH_BARRIER_BIT,
+            Barrier::Images           => gl::SHADER_IMAGE_ACCESS_BARRIER_BIT,
+            Barrier::Download         => gl::BUFFER_UPDATE_BARRIER_BIT,
+            Barrier::All              => gl::ALL_BARRIER_BITS,
+        }
+    }
+}
+
+// Wait for what the dispatches so far wrote before it is used in any of the `barriers` ways
+pub unsafe fn memory_barrier(barriers: &[Barrier]) {
+    let bits = barriers.iter().fold(0, |bits, &barrier| bits | GLenum::from(barrier));
+    if bits != 0 {
+        gl::MemoryBarrier(bits);
+    }
+}
@@ -0,0 +1,296 @@
+extern crate nalgebra_glm as glm;
+
+use crate::animation::Animator;
+use crate::bvh::{self, Aabb};
+use crate::helicopter::{Helicopter, HelicopterControls};
+use crate::scene_graph::SceneNode;
+use crate::spline::SplinePath;
+use crate::toolbox;
+use std::collections::HashMap;
+
+// A small entity-component system on top of the scene graph.
+//
+// An entity is just an id. Its data lives in one `Storage` per kind of component in the `World`,
+// indexed by that id, and the systems at the bottom of this file run over every entity that has
+// the components they need. The scene graph still does the drawing: an entity's `Transform` is
+// the node that places it, and its `Renderable` names the nodes it is drawn with.
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Entity(pub u32);
+
+// One kind of component, for any number of entities
+pub struct Storage<T> {
+    items: Vec<Option<T>>,
+}
+
+impl<T> Storage<T> {
+    pub fn new() -> Self {
+        Storage { items: vec![] }
+    }
+
+    pub fn insert(&mut self, entity: Entity, component: T) {
+        let index = entity.0 as usize;
+        if index >= self.items.len() {
+            self.items.resize_with(index + 1, || None);
+        }
+        self.items[index] = Some(component);
+    }
+
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        self.items.get_mut(entity.0 as usize).and_then(|item| item.take())
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        self.items.get(entity.0 as usize).and_then(|item| item.as_ref())
+    }
+
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        self.items.get_mut(entity.0 as usize).and_then(|item| item.as_mut())
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.get(entity).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> {
+        self.items.iter().enumerate()
+            .filter_map(|(i, item)| item.as_ref().map(|component| (Entity(i as u32), component)))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> {
+        self.items.iter_mut().enumerate()
+            .filter_map(|(i, item)| item.as_mut().map(|component| (Entity(i as u32), component)))
+    }
+
+    // The entities with a component in both this storage and `other`, with both components
+    pub fn join_mut<'a, U>(&'a mut self, other: &'a Storage<U>) -> impl Iterator<Item = (Entity, &'a mut T, &'a U)> {
+        self.items.iter_mut().zip(&other.items).enumerate().filter_map(|(i, pair)| match pair {
+            (Some(component), Some(other_component)) => Some((Entity(i as u32), component, other_component)),
+            _ => None,
+        })
+    }
+}
+
+// Components
+
+// The scene node placing the entity in the world. Systems write its position and rotation.
+pub struct Transform {
+    pub node: *mut SceneNode,
+}
+
+impl Transform {
+    pub fn new(node: &SceneNode) -> Self {
+        Transform { node: node as *const SceneNode as *mut SceneNode }
+    }
+}
+
+// The nodes the entity is drawn with, by name, so systems can find e.g. a helicopter's door
+// without knowing the order it was added to the tree in
+pub struct Renderable {
+    pub parts: HashMap<String, *mut SceneNode>,
+}
+
+impl Renderable {
+    pub fn new() -> Self {
+        Renderable { parts: HashMap::new() }
+    }
+
+    // Every named node below `root`, by its path from `root`, e.g. "Body/Door"
+    pub fn from_node(root: &SceneNode) -> Self {
+        let mut renderable = Renderable::new();
+        for node in root.depth_first().skip(1).filter(|node| !node.name.is_empty()) {
+            if let Some(path) = root.path_to(node) {
+                renderable = renderable.with_part(&path, node);
+            }
+        }
+        renderable
+    }
+
+    pub fn with_part(mut self, name: &str, node: &SceneNode) -> Self {
+        self.parts.insert(name.to_string(), node as *const SceneNode as *mut SceneNode);
+        self
+    }
+
+    pub fn part(&self, name: &str) -> Option<*mut SceneNode> {
+        self.parts.get(name).copied()
+    }
+}
+
+// Keyframe animation of one of the entity's parts
+pub struct PartAnimator {
+    pub part     : String,
+    pub animator : Animator,
+}
+
+// What moves the entity
+pub enum Controller {
+    Player,                        // flown with the shared `HelicopterControls`
+    Path(SplinePath),              // follows a spline path
+    Circuit { offset: glm::Vec3 }, // follows `toolbox::simple_heading_animation`, moved by `offset`
+}
+
+// World space bounds of the entity, kept up to date by `collider_system`
+pub struct Collider {
+    pub bounds: Aabb,
+}
+
+pub struct World {
+    next_entity      : u32,
+    pub names        : Storage<String>,
+    pub transforms   : Storage<Transform>,
+    pub renderables  : Storage<Renderable>,
+    pub animators    : Storage<Vec<PartAnimator>>,
+    pub controllers  : Storage<Controller>,
+    pub helicopters  : Storage<Helicopter>,
+    pub colliders    : Storage<Collider>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        World {
+            next_entity : 0,
+            names       : Storage::new(),
+            transforms  : Storage::new(),
+            renderables : Storage::new(),
+            animators   : Storage::new(),
+            controllers : Storage::new(),
+            helicopters : Storage::new(),
+            colliders   : Storage::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, name: &str) -> Entity {
+        let entity = Entity(self.next_entity);
+        self.next_entity += 1;
+        self.names.insert(entity, name.to_string());
+        entity
+    }
+
+    pub fn despawn(&mut self, entity: Entity) {
+        self.names.remove(entity);
+        self.transforms.remove(entity);
+        self.renderables.remove(entity);
+        self.animators.remove(entity);
+        self.controllers.remove(entity);
+        self.helicopters.remove(entity);
+        self.colliders.remove(entity);
+    }
+
+    pub fn find(&self, name: &str) -> Option<Entity> {
+        self.names.iter().find(|(_, n)| n.as_str() == name).map(|(entity, _)| entity)
+    }
+
+    // Run every system, in order, for one update of `timestep` seconds ending at `time`
+    pub fn update(&mut self, context: &UpdateContext) {
+        control_system(self, context);
+        rotor_system(self);
+        animation_system(self, context);
+        collider_system(self, context);
+    }
+}
+
+// What the systems need from outside the world for an update
+pub struct UpdateContext<'a> {
+    pub time        : f32, // simulated seconds at the end of the update
+    pub timestep    : f32,
+    pub controls    : &'a HelicopterControls,
+    pub ground      : &'a bvh::Bvh,
+    pub scene_root  : &'a SceneNode,
+    pub mesh_bounds : &'a HashMap<u32, Aabb>,
+}
+
+// Systems
+
+// Move every controlled entity, flying the helicopters among them
+pub fn control_system(world: &mut World, context: &UpdateContext) {
+    for (entity, controller) in world.controllers.iter_mut() {
+        let transform = match world.transforms.get(entity) {
+            Some(transform) => unsafe { &mut *transform.node },
+            None => continue,
+        };
+        let heli = world.helicopters.get_mut(entity);
+        match controller {
+            Controller::Player => {
+                if let Some(heli) = heli {
+                    heli.step(context.controls, context.timestep, |x, z| context.ground.height_at(x, z));
+                    transform.position = heli.position;
+                    transform.rotation = heli.rotation();
+                }
+            }
+            Controller::Path(path) => {
+                let heading = path.heading_at(context.time);
+                transform.position = glm::vec3(heading.x, heading.y, heading.z);
+                transform.rotation = heading.rotation();
+                // hovering along
+                if let Some(heli) = heli {
+                    heli.update_rotors(0.5, context.timestep);
+                }
+            }
+            Controller::Circuit { offset } => {
+                let heading = toolbox::simple_heading_animation(context.time * 0.5);
+                transform.position = *offset + glm::vec3(heading.x, heading.y, heading.z);
+                transform.rotation = glm::vec3(heading.pitch, heading.yaw, heading.roll);
+                if let Some(heli) = heli {
+                    heli.update_rotors(0.5, context.timestep);
+                }
+            }
+        }
+    }
+}
+
+// Turn the rotors of every helicopter as fast as its flight model spins them
+pub fn rotor_system(world: &mut World) {
+    for (entity, heli) in world.helicopters.iter() {
+        let renderable = match world.renderables.get(entity) {
+            Some(renderable) => renderable,
+            None => continue,
+        };
+        if let Some(rotor) = renderable.part("MainRotor") {
+            unsafe { (*rotor).rotation = glm::vec3(0.0, heli.main_rotor_angle, 0.0) };
+        }
+        if let Some(rotor) = renderable.part("TailRotor") {
+            unsafe { (*rotor).rotation = glm::vec3(heli.tail_rotor_angle, 0.0, 0.0) };
+        }
+    }
+}
+
+// Play the keyframe animations on every animated part
+pub fn animation_system(world: &mut World, context: &UpdateContext) {
+    for (entity, animators) in world.animators.iter_mut() {
+        let renderable = world.renderables.get(entity);
+        for part_animator in animators.iter_mut() {
+            part_animator.animator.update(context.timestep);
+            if let Some(node) = renderable.and_then(|renderable| renderable.part(&part_animator.part)) {
+                part_animator.animator.apply(unsafe { &mut *node });
+            }
+        }
+    }
+}
+
+// Fit every collider around wherever its entity is now
+pub fn collider_system(world: &mut World, context: &UpdateContext) {
+    // the placed nodes with colliders, and where each of them is, found in one walk down the tree
+    let mut placed: HashMap<*const SceneNode, Option<glm::Mat4>> = world.transforms.iter()
+        .filter(|&(entity, _)| world.colliders.contains(entity))
+        .map(|(_, transform)| (transform.node as *const SceneNode, None))
+        .collect();
+    find_transforms(context.scene_root, &glm::identity(), &mut placed);
+
+    for (_, collider, transform) in world.colliders.join_mut(&world.transforms) {
+        if let Some(node_transform) = placed[&(transform.node as *const SceneNode)] {
+            let node = unsafe { &*transform.node };
+            collider.bounds = bvh::subtree_bounds(node, &node_transform, context.mesh_bounds);
+        }
+    }
+}
+
+// Fill in the transforms of the nodes in `placed` found below `node`, which `parent_transform` places
+fn find_transforms(node: &SceneNode, parent_transform: &glm::Mat4, placed: &mut HashMap<*const SceneNode, Option<glm::Mat4>>) {
+    let transform = parent_transform * node.local_transform();
+    if let Some(slot) = placed.get_mut(&(node as *const SceneNode)) {
+        *slot = Some(transform);
+    }
+    for child in node.children() {
+        find_transforms(child, &transform, placed);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::animation::Sample;
+use crate::scene_graph::SceneNode;
+
+// Fixed timestep simulation, decoupled from the frame rate.
+//
+// Every frame hands the time it took to `GameLoop::advance`, which says how many updates of
+// exactly `timestep()` seconds to run. Time left over is carried into the next frame, so the
+// simulation runs at the same rate whatever the frame rate, and runs the same steps every time
+// it is given the same frame times. Rendering then sits somewhere between the last two updates,
+// `alpha` of the way from the previous one to the latest one.
+//
+// The clock can be paused, stepped one update at a time while paused, and slowed down or sped up.
+
+// The accumulator counts time in these fractions of an update, so that whole updates are taken
+// out of it exactly and the simulation never drifts from the clock
+const STEP_UNITS : u64 = 1 << 32;
+
+pub struct GameLoop {
+    pub time_scale     : f32, // simulated seconds per real second
+    pub max_frame_time : f32, // longer frames are cut short, rather than running ever more updates to catch up
+    updates_per_second : f64,
+    paused             : bool,
+    pending_steps      : u32, // single steps asked for while paused
+    accumulator        : u64, // simulated time not yet covered by an update, in `STEP_UNITS` per update
+    ticks              : u64, // updates run so far
+}
+
+impl GameLoop {
+    pub fn new(updates_per_second: f32) -> Self {
+        GameLoop {
+            time_scale         : 1.0,
+            max_frame_time     : 0.25,
+            updates_per_second : f64::from(updates_per_second),
+            paused             : false,
+            pending_steps      : 0,
+            accumulator        : 0,
+            ticks              : 0,
+        }
+    }
+
+    // Seconds simulated by each update
+    pub fn timestep(&self) -> f32 {
+        (1.0 / self.updates_per_second) as f32
+    }
+
+    // The number of updates to run for a frame that took `frame_time` real seconds. Call `tick`
+    // at the start of each one, after which `time` is the time it simulates up to.
+    pub fn advance(&mut self, frame_time: f32) -> u32 {
+        if self.paused {
+            let steps = self.pending_steps;
+            self.pending_steps = 0;
+            return steps;
+        }
+        let updates = f64::from(frame_time.clamp(0.0, self.max_frame_time)) * f64::from(self.time_scale) * self.updates_per_second;
+        self.accumulator += (updates * STEP_UNITS as f64).round() as u64;
+        let steps = self.accumulator / STEP_UNITS;
+        self.accumulator %= STEP_UNITS;
+        steps as u32
+    }
+
+    // Account for one update running
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    // How far rendering is from the previous update towards the latest one, in [0, 1). While
+    // paused the latest update is shown as it is.
+    pub fn alpha(&self) -> f32 {
+        if self.paused {
+            1.0
+        } else {
+            // close enough to a whole update rounds up to 1.0 as an f32
+            ((self.accumulator as f64 / STEP_UNITS as f64) as f32).min(1.0 - f32::EPSILON)
+        }
+    }
+
+    // Simulated seconds so far
+    pub fn time(&self) -> f32 {
+        (self.ticks as f64 / self.updates_per_second) as f32
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.pending_steps = 0;
+        self.accumulator = 0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.set_paused(!self.paused);
+    }
+
+    // Run a single update on the next frame, while paused
+    pub fn step(&mut self) {
+        if self.paused {
+            self.pending_steps += 1;
+        }
+    }
+
+    // Multiply the time scale by `factor`, keeping it within [1/16, 8]
+    pub fn scale_time(&mut self, factor: f32) {
+        self.time_scale = (self.time_scale * factor).clamp(1.0 / 16.0, 8.0);
+    }
+}
+
+// Scene nodes moved by the simulation. Their state after the last two updates is kept, so they
+// can be drawn in between.
+pub struct Interpolator {
+    nodes    : Vec<*mut SceneNode>,
+    previous : Vec<Sample>,
+    current  : Vec<Sample>,
+}
+
+impl Interpolator {
+    pub fn new(nodes: Vec<*mut SceneNode>) -> Self {
+        let mut interpolator = Interpolator { nodes, previous: vec![], current: vec![] };
+        interpolator.capture();
+        interpolator.previous = interpolator.current.clone();
+        interpolator
+    }
+
+    // Remember where the nodes are after an update. The update must set every property it
+    // moves, since the nodes hold interpolated values in between.
+    pub fn capture(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+        self.current = self.nodes.iter().map(|&node| {
+            let node = unsafe { &*node };
+            Sample { position: Some(node.position), rotation: Some(node.rotation), scale: Some(node.scale) }
+        }).collect();
+    }
+
+    // Put the nodes `alpha` of the way from the previous update to the latest one
+    pub fn apply(&self, alpha: f32) {
+        for ((&node, previous), current) in self.nodes.iter().zip(&self.previous).zip(&self.current) {
+            previous.blend(current, alpha).apply(unsafe { &mut *node });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed `frames` to `game_loop`, running their updates and returning how many there were
+    fn run(game_loop: &mut GameLoop, frames: &[f32]) -> u32 {
+        let mut steps = 0;
+        for &frame in frames {
+            let frame_steps = game_loop.advance(frame);
+            for _ in 0..frame_steps {
+                game_loop.tick();
+            }
+            steps += frame_steps;
+        }
+        steps
+    }
+
+    #[test]
+    fn same_updates_whatever_the_frame_rate() {
+        let mut one_frame = GameLoop::new(60.0);
+        one_frame.max_frame_time = 1.0;
+        let mut many_frames = GameLoop::new(60.0);
+        many_frames.max_frame_time = 1.0;
+
+        let one_frame_steps = run(&mut one_frame, &[1.0]);
+        let many_frames_steps = run(&mut many_frames, &[1.0 / 60.0; 60]);
+        assert_eq!(one_frame_steps, 60);
+        assert_eq!(many_frames_steps, 60);
+        assert_eq!(one_frame.ticks(), many_frames.ticks());
+        assert_eq!(one_frame.time(), many_frames.time());
+    }
+
+    #[test]
+    fn simulated_time_keeps_to_the_clock() {
+        let mut game_loop = GameLoop::new(60.0);
+        let frame = 1.0 / 144.0;
+        let frames = 144 * 60 * 30;
+        run(&mut game_loop, &vec![frame; frames]);
+        // half an hour in, the simulation is within an update of the time the frames took
+        let elapsed = f64::from(frame) * frames as f64;
+        let simulated = game_loop.ticks() as f64 / 60.0;
+        assert!(simulated <= elapsed && elapsed - simulated < 1.0 / 60.0, "{} against {}", simulated, elapsed);
+    }
+
+    #[test]
+    fn alpha_stays_below_one_while_running() {
+        let mut game_loop = GameLoop::new(60.0);
+        for i in 0..1000 {
+            // frames of many lengths, some shorter and some longer than an update
+            let frame = 0.001 + (i % 37) as f32 * 0.0013;
+            run(&mut game_loop, &[frame]);
+            let alpha = game_loop.alpha();
+            assert!((0.0..1.0).contains(&alpha), "alpha {} after frame {}", alpha, i);
+        }
+    }
+
+    #[test]
+    fn paused_loop_only_runs_single_steps() {
+        let mut game_loop = GameLoop::new(60.0);
+        game_loop.toggle_pause();
+        assert!(game_loop.is_paused());
+        assert_eq!(game_loop.advance(0.1), 0);
+        // the latest update is shown as it is
+        assert_eq!(game_loop.alpha(), 1.0);
+
+        game_loop.step();
+        game_loop.step();
+        assert_eq!(game_loop.advance(0.0), 2);
+        assert_eq!(game_loop.advance(0.1), 0);
+
+        // steps only count while paused
+        game_loop.toggle_pause();
+        game_loop.step();
+        assert_eq!(game_loop.advance(0.0), 0);
+    }
+
+    #[test]
+    fn time_scale_changes_the_updates_per_frame() {
+        let mut game_loop = GameLoop::new(60.0);
+        game_loop.scale_time(0.5);
+        assert_eq!(run(&mut game_loop, &[0.1; 6]), 18);
+
+        let mut game_loop = GameLoop::new(60.0);
+        game_loop.scale_time(2.0);
+        assert_eq!(run(&mut game_loop, &[0.1; 6]), 72);
+
+        // kept within [1/16, 8]
+        game_loop.scale_time(100.0);
+        assert_eq!(game_loop.time_scale, 8.0);
+        game_loop.scale_time(0.0);
+        assert_eq!(game_loop.time_scale, 1.0 / 16.0);
+    }
+
+    #[test]
+    fn long_frames_are_cut_short() {
+        let mut game_loop = GameLoop::new(60.0);
+        // a 10 second hitch runs no more updates than `max_frame_time` covers
+        assert_eq!(game_loop.advance(10.0), 15);
+        assert!(game_loop.alpha() < 1.0);
+        // nor does a negative frame take any back
+        assert_eq!(game_loop.advance(-1.0), 0);
+    }
+}
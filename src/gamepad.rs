@@ -0,0 +1,332 @@
+use serde::{Deserialize, Serialize};
+
+// Gamepad support. A `GamepadBackend` is polled once per frame for the state of the active pad,
+// which then travels alongside the keyboard and mouse in the `InputFrame`, so gamepad buttons and
+// sticks can be bound to the same actions and axes.
+//
+// The real backend uses gilrs and is behind the `gamepad` feature. `VirtualGamepad` is always
+// available, and is driven from code, e.g. to inject input without any hardware attached.
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum GamepadButton {
+    South,        // A on Xbox pads, cross on PlayStation
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    Select,
+    Start,
+    LeftStick,    // pressing the stick in
+    RightStick,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum GamepadAxis {
+    LeftStickX,   // positive to the right
+    LeftStickY,   // positive up
+    RightStickX,
+    RightStickY,
+    LeftTrigger,  // [0, 1]
+    RightTrigger,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum AxisDirection {
+    Positive,
+    Negative,
+}
+
+// Raw state of one gamepad, before dead zones are applied
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GamepadState {
+    pub buttons : Vec<GamepadButton>,
+    pub axes    : Vec<(GamepadAxis, f32)>,
+}
+
+impl GamepadState {
+    pub fn is_pressed(&self, button: GamepadButton) -> bool {
+        self.buttons.contains(&button)
+    }
+
+    pub fn value(&self, axis: GamepadAxis) -> f32 {
+        self.axes.iter().find(|(a, _)| *a == axis).map_or(0.0, |(_, v)| *v)
+    }
+
+    pub fn set_pressed(&mut self, button: GamepadButton, pressed: bool) {
+        self.buttons.retain(|&b| b != button);
+        if pressed {
+            self.buttons.push(button);
+        }
+    }
+
+    pub fn set_value(&mut self, axis: GamepadAxis, value: f32) {
+        self.axes.retain(|(a, _)| *a != axis);
+        if value != 0.0 {
+            self.axes.push((axis, value.clamp(-1.0, 1.0)));
+        }
+    }
+}
+
+// Rescales `value` so it starts from zero at the edge of the dead zone and still reaches one
+pub fn apply_dead_zone(value: f32, dead_zone: f32) -> f32 {
+    let magnitude = value.abs();
+    if magnitude <= dead_zone || dead_zone >= 1.0 {
+        return 0.0;
+    }
+    value.signum() * ((magnitude - dead_zone) / (1.0 - dead_zone)).min(1.0)
+}
+
+pub trait GamepadBackend {
+    // The current state of the active gamepad, or an idle state if none is connected
+    fn poll(&mut self) -> GamepadState;
+}
+
+// A gamepad that only exists in code. Set its buttons and axes, and it reports them when polled.
+#[derive(Default)]
+pub struct VirtualGamepad {
+    pub state: GamepadState,
+}
+
+impl VirtualGamepad {
+    pub fn new() -> Self {
+        VirtualGamepad::default()
+    }
+
+    pub fn press(&mut self, button: GamepadButton) {
+        self.state.set_pressed(button, true);
+    }
+
+    pub fn release(&mut self, button: GamepadButton) {
+        self.state.set_pressed(button, false);
+    }
+
+    pub fn set_axis(&mut self, axis: GamepadAxis, value: f32) {
+        self.state.set_value(axis, value);
+    }
+
+    // Let go of every button and centre every stick
+    pub fn reset(&mut self) {
+        self.state = GamepadState::default();
+    }
+}
+
+impl GamepadBackend for VirtualGamepad {
+    fn poll(&mut self) -> GamepadState {
+        self.state.clone()
+    }
+}
+
+// Opens the gilrs backend if available, otherwise a virtual gamepad nobody touches
+pub fn open_backend() -> Box<dyn GamepadBackend> {
+    #[cfg(feature = "gamepad")]
+    match GilrsBackend::new() {
+        Ok(backend) => return Box::new(backend),
+        Err(e) => println!("Gamepad support unavailable: {}", e),
+    }
+    Box::new(VirtualGamepad::new())
+}
+
+// Hardware gamepads through gilrs. The pad that most recently sent an event is the active one.
+
+#[cfg(feature = "gamepad")]
+pub struct GilrsBackend {
+    gilrs  : gilrs::Gilrs,
+    active : Option<gilrs::GamepadId>,
+}
+
+#[cfg(feature = "gamepad")]
+impl GilrsBackend {
+    pub fn new() -> Result<Self, String> {
+        let gilrs = gilrs::Gilrs::new().map_err(|e| e.to_string())?;
+        let active = gilrs.gamepads().next().map(|(id, gamepad)| {
+            println!("Using gamepad: {}", gamepad.name());
+            id
+        });
+        Ok(GilrsBackend { gilrs, active })
+    }
+}
+
+#[cfg(feature = "gamepad")]
+impl GamepadBackend for GilrsBackend {
+    fn poll(&mut self) -> GamepadState {
+        use gilrs::{Axis, Button};
+
+        // drain the event queue, gilrs updates the gamepad state as a side effect
+        while let Some(event) = self.gilrs.next_event() {
+            if self.active != Some(event.id) {
+                println!("Using gamepad: {}", self.gilrs.gamepad(event.id).name());
+                self.active = Some(event.id);
+            }
+        }
+
+        let mut state = GamepadState::default();
+        let gamepad = match self.active.and_then(|id| self.gilrs.connected_gamepad(id)) {
+            Some(gamepad) => gamepad,
+            None => return state,
+        };
+
+        let buttons = [
+            (Button::South,        GamepadButton::South),
+            (Button::East,         GamepadButton::East),
+            (Button::North,        GamepadButton::North),
+            (Button::West,         GamepadButton::West),
+            (Button::LeftTrigger,  GamepadButton::LeftBumper),
+            (Button::RightTrigger, GamepadButton::RightBumper),
+            (Button::Select,       GamepadButton::Select),
+            (Button::Start,        GamepadButton::Start),
+            (Button::LeftThumb,    GamepadButton::LeftStick),
+            (Button::RightThumb,   GamepadButton::RightStick),
+            (Button::DPadUp,       GamepadButton::DPadUp),
+            (Button::DPadDown,     GamepadButton::DPadDown),
+            (Button::DPadLeft,     GamepadButton::DPadLeft),
+            (Button::DPadRight,    GamepadButton::DPadRight),
+        ];
+        for (button, ours) in buttons {
+            state.set_pressed(ours, gamepad.is_pressed(button));
+        }
+
+        let axes = [
+            (Axis::LeftStickX,  GamepadAxis::LeftStickX),
+            (Axis::LeftStickY,  GamepadAxis::LeftStickY),
+            (Axis::RightStickX, GamepadAxis::RightStickX),
+            (Axis::RightStickY, GamepadAxis::RightStickY),
+        ];
+        for (axis, ours) in axes {
+            state.set_value(ours, gamepad.value(axis));
+        }
+
+        // analog triggers are reported as buttons with a value
+        let triggers = [
+            (Button::LeftTrigger2,  GamepadAxis::LeftTrigger),
+            (Button::RightTrigger2, GamepadAxis::RightTrigger),
+        ];
+        for (button, ours) in triggers {
+            state.set_value(ours, gamepad.button_data(button).map_or(0.0, |data| data.value()));
+        }
+
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{Binding, Bindings, Input, InputFrame};
+
+    // Hand the pad's state to `input` as the next frame
+    fn next_frame(input: &mut Input, pad: &mut VirtualGamepad) {
+        input.update(InputFrame { gamepad: pad.poll(), ..InputFrame::default() });
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn sticks_are_rescaled_past_the_dead_zone() {
+        let mut bindings = Bindings::default_bindings();
+        bindings.gamepad.dead_zone = 0.2;
+        bindings.gamepad.dead_zones.insert(GamepadAxis::RightStickX, 0.5);
+        let mut input = Input::new(bindings);
+        let mut pad = VirtualGamepad::new();
+
+        pad.set_axis(GamepadAxis::LeftStickY, 0.15);
+        next_frame(&mut input, &mut pad);
+        assert_eq!(input.axis("move_forward"), 0.0);
+
+        pad.set_axis(GamepadAxis::LeftStickY, 0.6);
+        next_frame(&mut input, &mut pad);
+        assert_near(input.axis("move_forward"), 0.5);
+
+        pad.set_axis(GamepadAxis::LeftStickY, -1.0);
+        next_frame(&mut input, &mut pad);
+        assert_near(input.axis("move_forward"), -1.0);
+
+        // an axis with a dead zone of its own
+        pad.set_axis(GamepadAxis::RightStickX, 0.4);
+        next_frame(&mut input, &mut pad);
+        assert_eq!(input.axis("turn_yaw"), 0.0);
+        pad.set_axis(GamepadAxis::RightStickX, 0.75);
+        next_frame(&mut input, &mut pad);
+        assert_near(input.axis("turn_yaw"), 0.5);
+    }
+
+    #[test]
+    fn buttons_and_axes_follow_the_bindings() {
+        let mut bindings = Bindings::default();
+        bindings.bind_action("fire", Binding::GamepadButton(GamepadButton::South));
+        bindings.bind_action("boost", Binding::GamepadAxis(GamepadAxis::RightTrigger, AxisDirection::Positive));
+        bindings.bind_axis("steer",
+            Binding::GamepadAxis(GamepadAxis::LeftStickX, AxisDirection::Positive),
+            Binding::GamepadAxis(GamepadAxis::LeftStickX, AxisDirection::Negative));
+        let mut input = Input::new(bindings);
+        let mut pad = VirtualGamepad::new();
+
+        pad.press(GamepadButton::East);
+        pad.set_axis(GamepadAxis::LeftStickY, 1.0);
+        next_frame(&mut input, &mut pad);
+        // nothing bound to those
+        assert!(!input.held("fire"));
+        assert_eq!(input.axis("steer"), 0.0);
+
+        pad.press(GamepadButton::South);
+        pad.set_axis(GamepadAxis::LeftStickX, -1.0);
+        next_frame(&mut input, &mut pad);
+        assert!(input.held("fire"));
+        assert_eq!(input.axis("steer"), -1.0);
+
+        // a trigger counts as held past the press threshold
+        pad.set_axis(GamepadAxis::RightTrigger, 0.4);
+        next_frame(&mut input, &mut pad);
+        assert!(!input.held("boost"));
+        pad.set_axis(GamepadAxis::RightTrigger, 0.9);
+        next_frame(&mut input, &mut pad);
+        assert!(input.held("boost"));
+
+        pad.reset();
+        next_frame(&mut input, &mut pad);
+        assert!(!input.held("fire"));
+        assert!(!input.held("boost"));
+        assert_eq!(input.axis("steer"), 0.0);
+    }
+
+    #[test]
+    fn presses_and_releases_happen_once() {
+        let mut input = Input::new(Bindings::default_bindings());
+        let mut pad = VirtualGamepad::new();
+
+        pad.press(GamepadButton::North);
+        next_frame(&mut input, &mut pad);
+        assert!(input.pressed("open_door"));
+        assert!(input.held("open_door"));
+
+        // still held, but no longer new
+        next_frame(&mut input, &mut pad);
+        assert!(!input.pressed("open_door"));
+        assert!(input.held("open_door"));
+        assert!(!input.released("open_door"));
+
+        pad.release(GamepadButton::North);
+        next_frame(&mut input, &mut pad);
+        assert!(input.released("open_door"));
+        assert!(!input.held("open_door"));
+        next_frame(&mut input, &mut pad);
+        assert!(!input.released("open_door"));
+
+        // sticks pushed past the threshold press like buttons
+        let mut bindings = Bindings::default();
+        bindings.bind_action("menu_down", Binding::GamepadAxis(GamepadAxis::LeftStickY, AxisDirection::Negative));
+        let mut input = Input::new(bindings);
+        pad.set_axis(GamepadAxis::LeftStickY, -0.9);
+        next_frame(&mut input, &mut pad);
+        assert!(input.pressed("menu_down"));
+        pad.set_axis(GamepadAxis::LeftStickY, -0.95);
+        next_frame(&mut input, &mut pad);
+        assert!(!input.pressed("menu_down"));
+    }
+}
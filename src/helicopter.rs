@@ -0,0 +1,174 @@
+extern crate nalgebra_glm as glm;
+
+use crate::scene_graph::quat_to_euler;
+
+// A simple flight model for a helicopter flown by the player.
+//
+// The collective sets how fast the main rotor spins, and the thrust grows with the square of the
+// rotor speed, so the rotor has to spool up before the helicopter lifts off. Thrust acts along the
+// helicopter's up axis: the cyclic tilts it forwards, backwards and sideways, which turns part of
+// the thrust into horizontal movement. The pedals turn the helicopter, with the tail rotor doing
+// less the slower it spins. Gravity and quadratic drag act on top, and the ground stops it falling.
+
+// How fast the collective lever moves while its axis is held, per second
+pub const COLLECTIVE_RATE: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HelicopterControls {
+    pub collective   : f32, // [0, 1], 0.5 hovers with the default flight model
+    pub cyclic_pitch : f32, // [-1, 1], positive tilts the nose down to fly forwards
+    pub cyclic_roll  : f32, // [-1, 1], positive banks to the right
+    pub pedals       : f32, // [-1, 1], positive turns to the right
+}
+
+impl HelicopterControls {
+    // Move the collective lever, `axis` in [-1, 1] raises or lowers it
+    pub fn move_collective(&mut self, axis: f32, delta_time: f32) {
+        self.collective = (self.collective + axis * COLLECTIVE_RATE * delta_time).clamp(0.0, 1.0);
+    }
+}
+
+pub struct FlightModel {
+    pub mass             : f32, // kg
+    pub gravity          : f32, // m/s^2
+    pub max_thrust       : f32, // N at full rotor speed
+    pub drag             : f32, // N per (m/s)^2
+    pub max_rotor_speed  : f32, // radians per second of the main rotor
+    pub rotor_spool      : f32, // how quickly the rotor speed follows the collective, per second
+    pub tail_rotor_ratio : f32, // tail rotor turns per main rotor turn
+    pub max_tilt         : f32, // radians of pitch and roll at full cyclic
+    pub tilt_response    : f32, // how quickly the attitude follows the cyclic, per second
+    pub yaw_rate         : f32, // radians per second at full pedal and rotor speed
+    pub ground_friction  : f32, // how quickly sliding stops on the ground, per second
+}
+
+impl FlightModel {
+    pub fn new() -> Self {
+        let mass = 1000.0;
+        let gravity = 9.81;
+        FlightModel {
+            mass,
+            gravity,
+            max_thrust       : 2.0 * mass * gravity,
+            drag             : 3.0,
+            max_rotor_speed  : 720.0f32.to_radians(),
+            rotor_spool      : 1.5,
+            tail_rotor_ratio : 1.5,
+            max_tilt         : 25.0f32.to_radians(),
+            tilt_response    : 3.0,
+            yaw_rate         : 90.0f32.to_radians(),
+            ground_friction  : 4.0,
+        }
+    }
+
+    // Rotor speed at which the thrust exactly cancels gravity
+    pub fn hover_rotor_speed(&self) -> f32 {
+        self.max_rotor_speed * (self.mass * self.gravity / self.max_thrust).sqrt()
+    }
+}
+
+pub struct Helicopter {
+    pub model            : FlightModel,
+    pub position         : glm::Vec3,
+    pub velocity         : glm::Vec3,
+    pub yaw              : f32,       // heading, positive turns left like `SceneNode::rotation`
+    pub pitch            : f32,       // positive raises the nose
+    pub roll             : f32,       // positive banks to the left
+    pub rotor_speed      : f32,       // radians per second of the main rotor
+    pub main_rotor_angle : f32,
+    pub tail_rotor_angle : f32,
+    pub on_ground        : bool,
+}
+
+impl Helicopter {
+    pub fn new(position: glm::Vec3) -> Self {
+        Helicopter {
+            model            : FlightModel::new(),
+            position,
+            velocity         : glm::zero(),
+            yaw              : 0.0,
+            pitch            : 0.0,
+            roll             : 0.0,
+            rotor_speed      : 0.0,
+            main_rotor_angle : 0.0,
+            tail_rotor_angle : 0.0,
+            on_ground        : false,
+        }
+    }
+
+    // Turn to the heading first, then pitch and roll about the helicopter's own axes
+    pub fn orientation(&self) -> glm::Quat {
+        glm::quat_angle_axis(self.yaw, &glm::vec3(0.0, 1.0, 0.0))
+            * glm::quat_angle_axis(self.pitch, &glm::vec3(1.0, 0.0, 0.0))
+            * glm::quat_angle_axis(self.roll, &glm::vec3(0.0, 0.0, 1.0))
+    }
+
+    // The orientation as angles for `SceneNode::rotation`
+    pub fn rotation(&self) -> glm::Vec3 {
+        quat_to_euler(&self.orientation())
+    }
+
+    pub fn thrust(&self) -> f32 {
+        let fraction = self.rotor_speed / self.model.max_rotor_speed;
+        self.model.max_thrust * fraction * fraction
+    }
+
+    // Spool the rotors towards the speed the collective asks for, and turn them
+    pub fn update_rotors(&mut self, collective: f32, delta_time: f32) {
+        let target = self.model.max_rotor_speed * collective.clamp(0.0, 1.0).sqrt();
+        self.rotor_speed += (target - self.rotor_speed) * (1.0 - (-self.model.rotor_spool * delta_time).exp());
+        self.main_rotor_angle = (self.main_rotor_angle + self.rotor_speed * delta_time) % std::f32::consts::TAU;
+        self.tail_rotor_angle = (self.tail_rotor_angle
+            + self.rotor_speed * self.model.tail_rotor_ratio * delta_time) % std::f32::consts::TAU;
+    }
+
+    // Advance the simulation by one update of `dt` seconds, which the game loop keeps short and
+    // fixed. `ground_height` gives the height of the ground under a point, or None where there is
+    // no ground.
+    pub fn step<F: Fn(f32, f32) -> Option<f32>>(&mut self, controls: &HelicopterControls, dt: f32, ground_height: F) {
+        let model = &self.model;
+        let rotor_fraction = self.rotor_speed / model.max_rotor_speed;
+
+        // attitude follows the cyclic, the skids keep us level on the ground
+        let (target_pitch, target_roll) = if self.on_ground {
+            (0.0, 0.0)
+        } else {
+            (-controls.cyclic_pitch.clamp(-1.0, 1.0) * model.max_tilt,
+             -controls.cyclic_roll.clamp(-1.0, 1.0) * model.max_tilt)
+        };
+        let response = 1.0 - (-model.tilt_response * dt).exp();
+        self.pitch += (target_pitch - self.pitch) * response;
+        self.roll  += (target_roll  - self.roll)  * response;
+        self.yaw   -= controls.pedals.clamp(-1.0, 1.0) * model.yaw_rate * rotor_fraction * dt;
+
+        // forces
+        let up = glm::quat_rotate_vec3(&self.orientation(), &glm::vec3(0.0, 1.0, 0.0));
+        let mut force = up * self.thrust()
+                      - glm::vec3(0.0, model.mass * model.gravity, 0.0)
+                      - self.velocity * glm::length(&self.velocity) * model.drag;
+
+        // the ground pushes back rather than letting us sink into it
+        if self.on_ground && force.y < 0.0 {
+            force.y = 0.0;
+        }
+
+        self.velocity += force / model.mass * dt;
+        if self.on_ground {
+            let friction = (-model.ground_friction * dt).exp();
+            self.velocity.x *= friction;
+            self.velocity.z *= friction;
+        }
+        self.position += self.velocity * dt;
+
+        self.update_rotors(controls.collective, dt);
+
+        self.on_ground = false;
+        if let Some(ground) = ground_height(self.position.x, self.position.z) {
+            if self.position.y <= ground {
+                self.position.y = ground;
+                self.velocity.y = self.velocity.y.max(0.0);
+                self.on_ground = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use crate::gamepad::{self, AxisDirection, GamepadAxis, GamepadButton, GamepadState};
+use glutin::event::{ElementState, MouseButton, VirtualKeyCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Input mapping: named actions and axes bound to keys, mouse buttons, the scroll wheel and gamepads.
+//
+// The event loop feeds raw events into a shared `RawInput`. Once per frame the render thread
+// takes an `InputFrame` out of it and hands it to `Input`, which answers questions like
+// "is `open_door` held", "was it pressed this frame" and "how far is `move_forward` pushed".
+
+// The bindings that ship, used when `resources/input.ron` is missing or broken
+const DEFAULT_BINDINGS : &str = include_str!("../resources/input.ron");
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    ScrollUp,
+    ScrollDown,
+    GamepadButton(GamepadButton),
+    GamepadAxis(GamepadAxis, AxisDirection), // one half of an analog axis
+}
+
+// Two bindings pushing an axis in opposite directions
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct AxisBinding {
+    pub positive: Binding,
+    pub negative: Binding,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadSettings {
+    pub dead_zone       : f32,                      // stick values closer to the centre than this are ignored
+    pub dead_zones      : HashMap<GamepadAxis, f32>, // overrides the dead zone of single axes
+    pub press_threshold : f32,                      // how far an analog axis must move to count as pressed
+}
+
+impl Default for GamepadSettings {
+    fn default() -> Self {
+        GamepadSettings {
+            dead_zone       : 0.15,
+            dead_zones      : HashMap::new(),
+            press_threshold : 0.5,
+        }
+    }
+}
+
+impl GamepadSettings {
+    pub fn dead_zone(&self, axis: GamepadAxis) -> f32 {
+        *self.dead_zones.get(&axis).unwrap_or(&self.dead_zone)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Bindings {
+    pub actions : HashMap<String, Vec<Binding>>,
+    pub axes    : HashMap<String, Vec<AxisBinding>>,
+    #[serde(default)]
+    pub gamepad : GamepadSettings,
+}
+
+impl Bindings {
+    pub fn load(path: &str) -> Result<Bindings, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read input bindings {}: {}", path, e))?;
+        ron::from_str(&text)
+            .map_err(|e| format!("Failed to parse input bindings {}: {}", path, e))
+    }
+
+    // Loads the bindings from `path`, falling back on the built in defaults if that fails. A
+    // missing file is written with the defaults to be edited, a broken one is left alone.
+    pub fn load_or_default(path: &str) -> Bindings {
+        if !std::path::Path::new(path).exists() {
+            match std::fs::write(path, DEFAULT_BINDINGS) {
+                Ok(()) => println!("Wrote the default input bindings to {}", path),
+                Err(e) => println!("Failed to write input bindings {}: {}", path, e),
+            }
+            return Bindings::default_bindings();
+        }
+        match Bindings::load(path) {
+            Ok(bindings) => bindings,
+            Err(e) => {
+                println!("{}, using the default bindings", e);
+                Bindings::default_bindings()
+            }
+        }
+    }
+
+    pub fn bind_action(&mut self, action: &str, binding: Binding) {
+        self.actions.entry(action.to_string()).or_default().push(binding);
+    }
+
+    pub fn bind_axis(&mut self, axis: &str, positive: Binding, negative: Binding) {
+        self.axes.entry(axis.to_string()).or_default().push(AxisBinding { positive, negative });
+    }
+
+    pub fn is_bound(&self, action: &str, binding: Binding) -> bool {
+        self.actions.get(action).map_or(false, |bindings| bindings.contains(&binding))
+    }
+
+    pub fn default_bindings() -> Bindings {
+        ron::from_str(DEFAULT_BINDINGS).expect("the default input bindings should parse")
+    }
+}
+
+// Everything that happened to the input devices during one frame
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputFrame {
+    pub keys_held        : Vec<VirtualKeyCode>,
+    pub keys_pressed     : Vec<VirtualKeyCode>, // went down this frame, even if already released again
+    pub keys_released    : Vec<VirtualKeyCode>,
+    pub buttons_held     : Vec<MouseButton>,
+    pub buttons_pressed  : Vec<MouseButton>,
+    pub buttons_released : Vec<MouseButton>,
+    pub mouse_delta      : (f32, f32),          // pixels
+    pub scroll           : f32,                 // lines, positive away from the user
+    #[serde(default)]
+    pub gamepad          : GamepadState,        // polled once per frame, see `gamepad::GamepadBackend`
+}
+
+// Collects events as they arrive in the event loop, until the render thread takes them
+#[derive(Default)]
+pub struct RawInput {
+    frame: InputFrame,
+}
+
+impl RawInput {
+    pub fn handle_key(&mut self, key: VirtualKeyCode, state: ElementState) {
+        let frame = &mut self.frame;
+        match state {
+            ElementState::Pressed => {
+                // held keys repeat their press events, those aren't new presses
+                if !frame.keys_held.contains(&key) {
+                    frame.keys_held.push(key);
+                    frame.keys_pressed.push(key);
+                }
+            }
+            ElementState::Released => {
+                if frame.keys_held.contains(&key) {
+                    frame.keys_held.retain(|&k| k != key);
+                    frame.keys_released.push(key);
+                }
+            }
+        }
+    }
+
+    pub fn handle_button(&mut self, button: MouseButton, state: ElementState) {
+        let frame = &mut self.frame;
+        match state {
+            ElementState::Pressed => {
+                if !frame.buttons_held.contains(&button) {
+                    frame.buttons_held.push(button);
+                    frame.buttons_pressed.push(button);
+                }
+            }
+            ElementState::Released => {
+                if frame.buttons_held.contains(&button) {
+                    frame.buttons_held.retain(|&b| b != button);
+                    frame.buttons_released.push(button);
+                }
+            }
+        }
+    }
+
+    pub fn add_mouse_motion(&mut self, dx: f32, dy: f32) {
+        self.frame.mouse_delta.0 += dx;
+        self.frame.mouse_delta.1 += dy;
+    }
+
+    pub fn add_scroll(&mut self, lines: f32) {
+        self.frame.scroll += lines;
+    }
+
+    // Hands over everything since the last call, keeping only what is still held
+    pub fn take_frame(&mut self) -> InputFrame {
+        let frame = self.frame.clone();
+        self.frame.keys_pressed.clear();
+        self.frame.keys_released.clear();
+        self.frame.buttons_pressed.clear();
+        self.frame.buttons_released.clear();
+        self.frame.mouse_delta = (0.0, 0.0);
+        self.frame.scroll = 0.0;
+        frame
+    }
+}
+
+// The render thread's view of the input, in terms of actions and axes
+pub struct Input {
+    pub bindings     : Bindings,
+    frame            : InputFrame,
+    previous_gamepad : GamepadState, // gamepads are polled, so presses are found by comparing frames
+}
+
+impl Input {
+    pub fn new(bindings: Bindings) -> Self {
+        Input { bindings, frame: InputFrame::default(), previous_gamepad: GamepadState::default() }
+    }
+
+    // Call once per frame, before asking about any actions
+    pub fn update(&mut self, frame: InputFrame) {
+        self.previous_gamepad = std::mem::replace(&mut self.frame, frame).gamepad;
+    }
+
+    pub fn frame(&self) -> &InputFrame {
+        &self.frame
+    }
+
+    pub fn mouse_delta(&self) -> (f32, f32) {
+        self.frame.mouse_delta
+    }
+
+    pub fn scroll(&self) -> f32 {
+        self.frame.scroll
+    }
+
+    // How far one half of a gamepad axis is pushed, in [0, 1], after the dead zone
+    fn gamepad_axis_value(&self, state: &GamepadState, axis: GamepadAxis, direction: AxisDirection) -> f32 {
+        let value = gamepad::apply_dead_zone(state.value(axis), self.bindings.gamepad.dead_zone(axis));
+        match direction {
+            AxisDirection::Positive => value.max(0.0),
+            AxisDirection::Negative => (-value).max(0.0),
+        }
+    }
+
+    fn gamepad_held(&self, state: &GamepadState, binding: &Binding) -> bool {
+        match *binding {
+            Binding::GamepadButton(button) => state.is_pressed(button),
+            Binding::GamepadAxis(axis, direction) =>
+                self.gamepad_axis_value(state, axis, direction) >= self.bindings.gamepad.press_threshold,
+            _ => false,
+        }
+    }
+
+    fn binding_held(&self, binding: &Binding) -> bool {
+        match binding {
+            Binding::Key(key)       => self.frame.keys_held.contains(key),
+            Binding::Mouse(button)  => self.frame.buttons_held.contains(button),
+            Binding::ScrollUp       => self.frame.scroll > 0.0,
+            Binding::ScrollDown     => self.frame.scroll < 0.0,
+            Binding::GamepadButton(_) | Binding::GamepadAxis(..) => self.gamepad_held(&self.frame.gamepad, binding),
+        }
+    }
+
+    fn binding_pressed(&self, binding: &Binding) -> bool {
+        match binding {
+            Binding::Key(key)       => self.frame.keys_pressed.contains(key),
+            Binding::Mouse(button)  => self.frame.buttons_pressed.contains(button),
+            Binding::ScrollUp       => self.frame.scroll > 0.0,
+            Binding::ScrollDown     => self.frame.scroll < 0.0,
+            Binding::GamepadButton(_) | Binding::GamepadAxis(..) =>
+                self.gamepad_held(&self.frame.gamepad, binding) && !self.gamepad_held(&self.previous_gamepad, binding),
+        }
+    }
+
+    fn binding_released(&self, binding: &Binding) -> bool {
+        match binding {
+            Binding::Key(key)       => self.frame.keys_released.contains(key),
+            Binding::Mouse(button)  => self.frame.buttons_released.contains(button),
+            Binding::ScrollUp | Binding::ScrollDown => false,
+            Binding::GamepadButton(_) | Binding::GamepadAxis(..) =>
+                !self.gamepad_held(&self.frame.gamepad, binding) && self.gamepad_held(&self.previous_gamepad, binding),
+        }
+    }
+
+    // Buttons count as fully pushed, analog axes as far as they're pushed, the scroll wheel counts its lines
+    fn binding_value(&self, binding: &Binding) -> f32 {
+        match *binding {
+            Binding::ScrollUp   => self.frame.scroll.max(0.0),
+            Binding::ScrollDown => (-self.frame.scroll).max(0.0),
+            Binding::GamepadAxis(axis, direction) => self.gamepad_axis_value(&self.frame.gamepad, axis, direction),
+            _ => if self.binding_held(binding) { 1.0 } else { 0.0 },
+        }
+    }
+
+    fn any_binding(&self, action: &str, test: impl Fn(&Binding) -> bool) -> bool {
+        self.bindings.actions.get(action).map_or(false, |bindings| bindings.iter().any(test))
+    }
+
+    // Whether the action is held down right now
+    pub fn held(&self, action: &str) -> bool {
+        self.any_binding(action, |b| self.binding_held(b))
+    }
+
+    // Whether the action went down this frame
+    pub fn pressed(&self, action: &str) -> bool {
+        self.any_binding(action, |b| self.binding_pressed(b))
+    }
+
+    // Whether the action was let go this frame
+    pub fn released(&self, action: &str) -> bool {
+        self.any_binding(action, |b| self.binding_released(b))
+    }
+
+    // Buttons and sticks move the axis within [-1, 1], scrolling adds its lines on top of that
+    pub fn axis(&self, axis: &str) -> f32 {
+        let bindings = match self.bindings.axes.get(axis) {
+            Some(bindings) => bindings,
+            None => return 0.0,
+        };
+        let is_scroll = |b: &Binding| matches!(b, Binding::ScrollUp | Binding::ScrollDown);
+        let mut buttons = 0.0;
+        let mut scroll = 0.0;
+        for binding in bindings {
+            for (b, sign) in [(&binding.positive, 1.0), (&binding.negative, -1.0)] {
+                if is_scroll(b) {
+                    scroll += sign * self.binding_value(b);
+                } else {
+                    buttons += sign * self.binding_value(b);
+                }
+            }
+        }
+        buttons.clamp(-1.0, 1.0) + scroll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glutin::event::ElementState::{Pressed, Released};
+
+    #[test]
+    fn default_bindings_are_the_shipped_ones() {
+        let bindings = Bindings::default_bindings();
+        assert!(bindings.is_bound("quit", Binding::Key(VirtualKeyCode::Escape)));
+        assert_eq!(bindings.axes["move_forward"].len(), 2);
+        assert_eq!(bindings.gamepad.dead_zone(GamepadAxis::LeftTrigger), 0.05);
+        assert_eq!(bindings.gamepad.dead_zone(GamepadAxis::LeftStickX), 0.15);
+    }
+
+    #[test]
+    fn frames_keep_only_what_is_still_held() {
+        let mut raw = RawInput::default();
+        raw.handle_key(VirtualKeyCode::W, Pressed);
+        raw.handle_key(VirtualKeyCode::W, Pressed); // a key repeat
+        raw.handle_button(MouseButton::Left, Pressed);
+        raw.add_mouse_motion(3.0, -1.0);
+        raw.add_mouse_motion(2.0, 4.0);
+        raw.add_scroll(1.0);
+
+        let frame = raw.take_frame();
+        assert_eq!(frame.keys_held, [VirtualKeyCode::W]);
+        assert_eq!(frame.keys_pressed, [VirtualKeyCode::W]);
+        assert_eq!(frame.buttons_pressed, [MouseButton::Left]);
+        assert_eq!(frame.mouse_delta, (5.0, 3.0));
+        assert_eq!(frame.scroll, 1.0);
+
+        raw.handle_key(VirtualKeyCode::W, Released);
+        raw.handle_key(VirtualKeyCode::S, Released); // never pressed
+        let frame = raw.take_frame();
+        assert!(frame.keys_held.is_empty());
+        assert!(frame.keys_pressed.is_empty());
+        assert_eq!(frame.keys_released, [VirtualKeyCode::W]);
+        assert_eq!(frame.buttons_held, [MouseButton::Left]);
+        assert!(frame.buttons_pressed.is_empty());
+        assert_eq!(frame.mouse_delta, (0.0, 0.0));
+        assert_eq!(frame.scroll, 0.0);
+    }
+
+    #[test]
+    fn actions_are_pressed_and_released_once() {
+        let mut bindings = Bindings::default();
+        bindings.bind_action("jump", Binding::Key(VirtualKeyCode::Space));
+        bindings.bind_action("fire", Binding::Mouse(MouseButton::Left));
+        let mut input = Input::new(bindings);
+        let mut raw = RawInput::default();
+
+        raw.handle_key(VirtualKeyCode::Space, Pressed);
+        raw.handle_button(MouseButton::Left, Pressed);
+        input.update(raw.take_frame());
+        for action in ["jump", "fire"] {
+            assert!(input.pressed(action) && input.held(action) && !input.released(action), "{}", action);
+        }
+
+        input.update(raw.take_frame());
+        for action in ["jump", "fire"] {
+            assert!(!input.pressed(action) && input.held(action) && !input.released(action), "{}", action);
+        }
+
+        raw.handle_key(VirtualKeyCode::Space, Released);
+        raw.handle_button(MouseButton::Left, Released);
+        input.update(raw.take_frame());
+        for action in ["jump", "fire"] {
+            assert!(!input.pressed(action) && !input.held(action) && input.released(action), "{}", action);
+        }
+
+        input.update(raw.take_frame());
+        for action in ["jump", "fire"] {
+            assert!(!input.pressed(action) && !input.held(action) && !input.released(action), "{}", action);
+        }
+        assert!(!input.pressed("unbound"));
+    }
+
+    #[test]
+    fn taps_within_a_frame_still_press() {
+        let mut bindings = Bindings::default();
+        bindings.bind_action("jump", Binding::Key(VirtualKeyCode::Space));
+        bindings.bind_action("fire", Binding::Mouse(MouseButton::Left));
+        let mut input = Input::new(bindings);
+        let mut raw = RawInput::default();
+
+        raw.handle_key(VirtualKeyCode::Space, Pressed);
+        raw.handle_key(VirtualKeyCode::Space, Released);
+        raw.handle_button(MouseButton::Left, Pressed);
+        raw.handle_button(MouseButton::Left, Released);
+        input.update(raw.take_frame());
+        for action in ["jump", "fire"] {
+            assert!(input.pressed(action) && input.released(action) && !input.held(action), "{}", action);
+        }
+
+        input.update(raw.take_frame());
+        for action in ["jump", "fire"] {
+            assert!(!input.pressed(action) && !input.released(action), "{}", action);
+        }
+    }
+}
@@ -8,33 +8,60 @@
 #![allow(unused_variables)]
 
 extern crate nalgebra_glm as glm;
+use std::collections::HashMap;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::{mem, os::raw::c_void, ptr};
 
 // assignment 3
 mod shader;
+mod preprocessor;
+mod program_cache;
 mod util;
 mod mesh;
 mod scene_graph;
 mod toolbox;
+mod bvh;
+mod camera;
+mod camera_controller;
+mod input;
+mod gamepad;
+mod replay;
+mod helicopter;
+mod animation;
+mod spline;
+mod game_loop;
+mod ecs;
+mod scene;
+mod renderer;
+mod uniform_buffer;
+mod compute;
+mod terrain;
 
 use scene_graph::SceneNode;
 use gl::{BufferData, GenBuffers};
 use glutin::event::{
     DeviceEvent,
-    ElementState::{Pressed, Released},
-    Event, KeyboardInput,
-    VirtualKeyCode::{self, *},
+    ElementState::Pressed,
+    Event, KeyboardInput, MouseScrollDelta,
     WindowEvent,
 };
 use glutin::event_loop::ControlFlow;
-use toolbox::Heading;
+use camera::Camera;
 
 // initial window size
 const INITIAL_SCREEN_W: u32 = 800;
 const INITIAL_SCREEN_H: u32 = 600;
 
+// which controller currently drives the camera
+#[derive(Clone, Copy, PartialEq)]
+enum CameraMode {
+    Fly,   // free flight with WASD and mouse look
+    Orbit, // circle the selected helicopter, drag to rotate, scroll to zoom, middle drag to pan
+    Chase, // follow behind the selected helicopter, or ride along in its cockpit
+}
+
 // == // Helper functions to make interacting with OpenGL a little bit prettier. You *WILL* need these! // == //
 
 // Get the size of an arbitrary array of numbers measured in bytes
@@ -140,6 +167,44 @@ unsafe fn create_vao(vertices: &Vec<f32>, indices: &Vec<u32>, colors:&Vec<f32>,
 
 
 
+// Build a program from `files` with the shader cache, or quit with the error
+unsafe fn build_shader(files: &[&str]) -> shader::Shader {
+    let mut builder = Ok(shader::ShaderBuilder::new().with_binary_cache("./shader_cache"));
+    for file in files {
+        builder = builder.and_then(|builder| builder.attach_file(file));
+    }
+    match builder.and_then(|builder| builder.link()) {
+        Ok(shader) => shader,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Confine and hide the cursor for mouse look, or release it again. Returns whether it ended up grabbed.
+fn set_cursor_grabbed(window: &glutin::window::Window, grab: bool) -> bool {
+    use glutin::window::CursorGrabMode;
+    if !grab {
+        let _ = window.set_cursor_grab(CursorGrabMode::None);
+        window.set_cursor_visible(true);
+        return false;
+    }
+    // not every platform supports both modes, so fall back on the other one
+    let grabbed = window.set_cursor_grab(CursorGrabMode::Confined)
+        .or_else(|_| window.set_cursor_grab(CursorGrabMode::Locked));
+    match grabbed {
+        Ok(()) => {
+            window.set_cursor_visible(false);
+            true
+        }
+        Err(e) => {
+            println!("Failed to grab cursor: {}", e);
+            false
+        }
+    }
+}
+
 fn main() {
     // Set up the necessary objects to deal with windows and event handling
     let el = glutin::event_loop::EventLoop::new();
@@ -152,19 +217,27 @@ fn main() {
         ));
     let cb = glutin::ContextBuilder::new().with_vsync(true);
     let windowed_context = cb.build_windowed(wb, &el).unwrap();
-    // Uncomment these if you want to use the mouse for controls, but want it to be confined to the screen and/or invisible.
-    // windowed_context.window().set_cursor_grab(true).expect("failed to grab cursor");
-    // windowed_context.window().set_cursor_visible(false);
+    // The cursor can be confined to the window and hidden for mouse look, see `set_cursor_grabbed`.
+    // Toggle it with G while running, or set this to start out grabbed.
+    let grab_cursor_on_start = false;
+
+    // Recording and replaying input is controlled from the command line, see `replay::ReplayOptions`
+    let replay_options = match replay::ReplayOptions::from_args(std::env::args()) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // Set up a shared vector for keeping track of currently pressed keys
-    let arc_pressed_keys = Arc::new(Mutex::new(Vec::<VirtualKeyCode>::with_capacity(10)));
-    // Make a reference of this vector to send to the render thread
-    let pressed_keys = Arc::clone(&arc_pressed_keys);
+    // Load the input bindings, the event loop needs them too to know which keys quit
+    let bindings = input::Bindings::load_or_default("./resources/input.ron");
+    let quit_bindings = bindings.clone();
 
-    // Set up shared tuple for tracking mouse movement between frames
-    let arc_mouse_delta = Arc::new(Mutex::new((0f32, 0f32)));
-    // Make a reference of this tuple to send to the render thread
-    let mouse_delta = Arc::clone(&arc_mouse_delta);
+    // Set up shared input state for collecting keys, mouse buttons, mouse movement and scrolling between frames
+    let arc_raw_input = Arc::new(Mutex::new(input::RawInput::default()));
+    // Make a reference of this state to send to the render thread
+    let raw_input = Arc::clone(&arc_raw_input);
 
     // Set up shared tuple for tracking changes to the window size
     let arc_window_size = Arc::new(Mutex::new((INITIAL_SCREEN_W, INITIAL_SCREEN_H, false)));
@@ -182,7 +255,22 @@ fn main() {
             c
         };
 
-        let mut window_aspect_ratio = INITIAL_SCREEN_W as f32 / INITIAL_SCREEN_H as f32;
+        let mut camera = camera::PerspectiveCamera::new(INITIAL_SCREEN_W as f32 / INITIAL_SCREEN_H as f32);
+        camera.pose.position = glm::vec3(0.0, 0.0, 2.0);
+        let fly_controller = camera_controller::FlyController::new();
+        let mut orbit_controller = camera_controller::OrbitController::new();
+        let mut chase_controller = camera_controller::ChaseController::chase();
+        let mut camera_mode = CameraMode::Fly;
+        let mut cursor_grabbed = grab_cursor_on_start && set_cursor_grabbed(context.window(), true);
+        // the helicopter the orbit and chase cameras follow, if the scene has any
+        let mut selected_heli: Option<usize> = None;
+        let mut frame_selected = false;
+        let mut input = input::Input::new(bindings);
+        let mut gamepad_backend = gamepad::open_backend();
+        let mut recorder = replay_options.record.as_ref()
+            .map(|path| replay::Recorder::create(path).expect("Failed to start recording"));
+        let mut player = replay_options.replay.as_ref()
+            .map(|path| replay::Player::load(path).expect("Failed to load replay"));
 
         // Set up openGL
         unsafe {
@@ -210,40 +298,129 @@ fn main() {
 
         // == // Set up your VAO around here
 
-        // terrain
-        let terrain_mesh = mesh::Terrain::load("./resources/lunarsurface.obj");
-        let terrain_vao = unsafe { create_vao(&terrain_mesh.vertices, &terrain_mesh.indices,&terrain_mesh.colors,&terrain_mesh.normals) };
-        let mut terrain_node = SceneNode::from_vao(terrain_vao,terrain_mesh.index_count);
-
-        // helicopter
-        let heli_mesh = mesh::Helicopter::load("./resources/helicopter.obj");
-        let body_vao = unsafe { create_vao(&heli_mesh.body.vertices, &heli_mesh.body.indices,&heli_mesh.body.colors,&heli_mesh.body.normals) };
-        let door_vao = unsafe { create_vao(&heli_mesh.door.vertices, &heli_mesh.door.indices,&heli_mesh.door.colors,&heli_mesh.door.normals) };
-        let main_rotor_vao = unsafe { create_vao(&heli_mesh.main_rotor.vertices, &heli_mesh.main_rotor.indices,&heli_mesh.main_rotor.colors,&heli_mesh.main_rotor.normals) };
-        let tail_rotor_vao = unsafe { create_vao(&heli_mesh.tail_rotor.vertices, &heli_mesh.tail_rotor.indices,&heli_mesh.tail_rotor.colors,&heli_mesh.tail_rotor.normals) };
-
-        // loop to draw 5 helicopters
-        let mut heli_all_parents :Vec<scene_graph::Node> = Vec::<scene_graph::Node>::new();
-        for n in 0..5 {
-            let mut heli_parent_node = SceneNode::new();
-            let mut body_node = SceneNode::from_vao(body_vao,heli_mesh.body.index_count);
-            let mut door_node = SceneNode::from_vao(door_vao,heli_mesh.door.index_count);
-            let mut main_rotor_node = SceneNode::from_vao(main_rotor_vao,heli_mesh.main_rotor.index_count);
-            let mut tail_rotor_node = SceneNode::from_vao(tail_rotor_vao,heli_mesh.tail_rotor.index_count);
-
-            heli_parent_node.add_child(&body_node);
-            body_node.add_child(&door_node);
-            heli_parent_node.add_child(&main_rotor_node);
-            heli_parent_node.add_child(&tail_rotor_node);
-
-            terrain_node.add_child(&heli_parent_node);
-
-            // set-up reference point
-            body_node.reference_point = glm::Vec3::new(0.0, 2.3, 0.0);
-            tail_rotor_node.reference_point = glm::Vec3::new(0.35, 2.3, 10.4);
-
-            heli_all_parents.push(heli_parent_node);
+        // the scene: meshes, materials, lights, cameras, animations and the node tree
+        let scene_path = "./resources/scene.ron";
+        let mut scene_file = scene::SceneFile::load(scene_path).expect("Failed to load scene");
+        let scene_meshes = scene_file.load_meshes().expect("Failed to load scene meshes");
+
+        // model space bounds of every VAO, for framing nodes with the camera
+        let mut mesh_bounds: HashMap<u32, bvh::Aabb> = HashMap::new();
+        // the mesh behind each VAO, for saving the scene back
+        let mut vao_names: HashMap<u32, String> = HashMap::new();
+        // draws every node sharing a mesh with a single instanced draw call, in its material's color
+        let mut renderer = renderer::Renderer::new();
+        // one VAO per mesh, shared by every node drawing it
+        let mut vaos: HashMap<String, (u32, i32)> = HashMap::new();
+        let mut terrain_node = scene_file.instantiate(&mut |mesh_name: &str| {
+            if let Some(vao) = vaos.get(mesh_name) {
+                return Ok(*vao);
+            }
+            let mesh = scene_meshes.get(mesh_name).ok_or(format!("Unknown mesh {}", mesh_name))?;
+            let vao_id = unsafe { create_vao(&mesh.vertices, &mesh.indices, &mesh.colors, &mesh.normals) };
+            mesh_bounds.insert(vao_id, bvh::Aabb::from_mesh(mesh));
+            let description = scene_file.meshes.iter().find(|description| description.name == mesh_name);
+            if let Some(patch_size) = description.and_then(|description| description.patch_size) {
+                renderer.set_patch_size(vao_id, patch_size);
+            }
+            vao_names.insert(vao_id, mesh_name.to_string());
+            vaos.insert(mesh_name.to_string(), (vao_id, mesh.index_count));
+            Ok((vao_id, mesh.index_count))
+        }).expect("Failed to build scene");
+
+        // ray queries against the terrain (picking, line of sight, ground height), which is the
+        // mesh of the node tagged "ground"
+        let ground_mesh = terrain_node.find_by_tag("ground").first()
+            .and_then(|node| vao_names.get(&node.vao_id))
+            .and_then(|mesh_name| scene_meshes.get(mesh_name))
+            .expect("The scene has no node tagged ground");
+        let terrain_bvh = bvh::Bvh::build(ground_mesh);
+
+        // start the camera where the scene says
+        if let Some(start) = scene_file.cameras.first() {
+            camera.pose.position = glm::vec3(start.position[0], start.position[1], start.position[2]);
+            camera.pose.yaw      = start.yaw.to_radians();
+            camera.pose.pitch    = start.pitch.to_radians();
+            camera.fov_y         = start.fov_y.to_radians();
+            camera.near          = start.near;
+            camera.far           = start.far;
+        }
+
+        // Every node with a controller becomes an entity in the world, which knows its parts by
+        // name. Opening a door plays its clip forwards, closing plays it backwards from wherever
+        // the door got to.
+        let mut world = ecs::World::new();
+        let mut heli_entities: Vec<ecs::Entity> = Vec::new();
+        let mut clips: HashMap<String, Rc<animation::Clip>> = HashMap::new();
+        let scene_nodes = scene_file.nodes_with_paths().expect("Failed to build scene");
+        for (path, description) in &scene_nodes {
+            let controller = match &description.controller {
+                Some(controller) => controller,
+                None => continue,
+            };
+            let node = match terrain_node.find_mut(path) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            let entity = world.spawn(&node.name);
+            world.transforms.insert(entity, ecs::Transform::new(node));
+            world.renderables.insert(entity, ecs::Renderable::from_node(node));
+            world.colliders.insert(entity, ecs::Collider { bounds: bvh::Aabb::empty() });
+
+            let mut animators = vec![];
+            for (part, clip_name) in &description.animations {
+                let clip = match clips.get(clip_name) {
+                    Some(clip) => Rc::clone(clip),
+                    None => match scene_file.clip(clip_name) {
+                        Some(clip) => Rc::clone(clips.entry(clip_name.clone()).or_insert(Rc::new(clip))),
+                        None => {
+                            println!("Unknown animation {} on {}", clip_name, path);
+                            continue;
+                        }
+                    },
+                };
+                let mut animator = animation::Animator::new();
+                animator.play(clip);
+                animator.set_speed(0.0); // still until asked
+                animators.push(ecs::PartAnimator { part: part.clone(), animator });
+            }
+            world.animators.insert(entity, animators);
+
+            // the player's helicopter starts out parked on the ground. The others fly their own
+            // routes, or the old circuit if their path fails to load, hovering with their rotors
+            // at speed.
+            let mut heli = helicopter::Helicopter::new(node.position);
+            let controller = match controller {
+                scene::ControllerDescription::Player => {
+                    let ground = terrain_bvh.height_at(node.position.x, node.position.z).unwrap_or(0.0);
+                    heli.position.y = heli.position.y.max(ground);
+                    heli.on_ground = true;
+                    ecs::Controller::Player
+                }
+                scene::ControllerDescription::Path(file) => {
+                    heli.rotor_speed = heli.model.hover_rotor_speed();
+                    match spline::SplinePath::load(file) {
+                        Ok(path) => ecs::Controller::Path(path),
+                        Err(e) => {
+                            println!("{}, using the default circuit", e);
+                            ecs::Controller::Circuit { offset: node.position }
+                        }
+                    }
+                }
+                scene::ControllerDescription::Circuit => {
+                    heli.rotor_speed = heli.model.hover_rotor_speed();
+                    ecs::Controller::Circuit { offset: node.position }
+                }
+            };
+            world.helicopters.insert(entity, heli);
+            world.controllers.insert(entity, controller);
+
+            heli_entities.push(entity);
+        }
+        if !heli_entities.is_empty() {
+            selected_heli = Some(0);
         }
+        let mut heli_controls = helicopter::HelicopterControls::default();
 
         // == // Set up your shaders here
 
@@ -253,42 +430,59 @@ fn main() {
         // The `.` in the path is relative to `Cargo.toml`.
         // This snippet is not enough to do the exercise, and will need to be modified (outside
         // of just using the correct path), but it only needs to be called once
-        unsafe {
-            let simple_shader = shader::ShaderBuilder::new()
-                .attach_file("./shaders/simple.vert")
-                .attach_file("./shaders/simple.frag")
-                .link();
-            simple_shader.activate();
-            
+        // Editing the shader files while running rebuilds them, see `Shader::reload_if_changed`.
+        // Linked programs are cached in `./shader_cache` to start faster the next time.
+        let mut simple_shader = unsafe { build_shader(&["./shaders/simple.vert", "./shaders/simple.frag"]) };
+
+        // the terrain is tessellated finer close to the camera and given detail by a heightmap
+        let mut terrain_shader = unsafe {
+            build_shader(&["./shaders/terrain.vert", "./shaders/terrain.tcs", "./shaders/terrain.tes", "./shaders/simple.frag"])
         };
+        let terrain_detail = terrain::TerrainDetail::default();
+        unsafe {
+            let heightmap_generator = build_shader(&["./shaders/terrain_heightmap.comp"]);
+            let heightmap = terrain::Heightmap::generate(512, &heightmap_generator);
+            heightmap.bind(terrain::HEIGHTMAP_UNIT);
+            gl::DeleteProgram(heightmap_generator.program_id);
+            terrain_detail.apply(&terrain_shader);
+        }
+
+        // the camera, the time and the lights, for every program to read
+        let camera_buffer = unsafe { uniform_buffer::UniformBuffer::<uniform_buffer::CameraBlock>::new(uniform_buffer::CAMERA_BINDING) };
+        let lights_buffer = unsafe { uniform_buffer::UniformBuffer::<uniform_buffer::LightsBlock>::new(uniform_buffer::LIGHTS_BINDING) };
+        unsafe { lights_buffer.update(&uniform_buffer::LightsBlock::new(&scene_file.lights)) };
 
         // Used to demonstrate keyboard handling for exercise 2.
         let mut _arbitrary_number = 0.0; // feel free to remove
 
-        // store keyboard input motion
-        let mut motionX : f32 = 0.0;
-        let mut motionY : f32 = 0.0;
-        let mut motionZ : f32 = 0.0;
-        let mut rotationYaw : f32 = 0.0;
-        let mut rotationPitch : f32 = 0.0;
-        let mut moveDoorX : f32 = 0.0;
-        let mut moveDoorZ : f32 = 0.0;
+        // the simulation runs at a fixed rate, and the nodes it moves are drawn in between updates
+        let mut game_loop = game_loop::GameLoop::new(60.0);
+        let mut simulated_nodes: Vec<*mut SceneNode> = Vec::new();
+        for (entity, transform) in world.transforms.iter() {
+            simulated_nodes.push(transform.node);
+            if let Some(renderable) = world.renderables.get(entity) {
+                simulated_nodes.extend(renderable.parts.values());
+            }
+        }
+        let mut interpolator = game_loop::Interpolator::new(simulated_nodes);
 
         // The main rendering loop
         let first_frame_time = std::time::Instant::now();
         let mut prevous_frame_time = first_frame_time;
         loop {
-            // Compute time passed since the previous frame and since the start of the program
+            // Compute time passed since the previous frame and since the start of the program. The frame
+            // time only follows the wall clock when neither replaying nor using a fixed timestep.
             let now = std::time::Instant::now();
-            let elapsed = now.duration_since(first_frame_time).as_secs_f32();
-            let delta_time = now.duration_since(prevous_frame_time).as_secs_f32();
+            let wall_time = now.duration_since(first_frame_time).as_secs_f32();
+            let mut delta_time = replay_options.fixed_timestep
+                .unwrap_or_else(|| now.duration_since(prevous_frame_time).as_secs_f32());
             prevous_frame_time = now;
 
             // Handle resize events
             if let Ok(mut new_size) = window_size.lock() {
                 if new_size.2 {
                     context.resize(glutin::dpi::PhysicalSize::new(new_size.0, new_size.1));
-                    window_aspect_ratio = new_size.0 as f32 / new_size.1 as f32;
+                    camera.resize(new_size.0, new_size.1);
                     (*new_size).2 = false;
                     println!("Resized");
                     unsafe {
@@ -299,143 +493,217 @@ fn main() {
 
             
 
-            if let Ok(keys) = pressed_keys.lock() {
-                for key in keys.iter() {
-                    match key {
-                        // The `VirtualKeyCode` enum is defined here:
-                        //    https://docs.rs/winit/0.25.0/winit/event/enum.VirtualKeyCode.html
-
-                        // Move sideways
-                        VirtualKeyCode::A => {
-                            motionX += 20.0 * delta_time;
-                        }
-                        VirtualKeyCode::D => {
-                            motionX -= 20.0 * delta_time;
-                        }
-
-                        // Move up/down
-                        VirtualKeyCode::S => {
-                            motionY += 20.0 * delta_time;
-                        }
-                        VirtualKeyCode::W => {
-                            motionY -= 20.0 * delta_time;
-                        }
-
-                        // Zoom in/out
-                        VirtualKeyCode::Space => {
-                            motionZ += 20.0 * delta_time;
-                        }
-                        VirtualKeyCode::LShift => {
-                            motionZ -= 20.0 * delta_time;
-                        }
+            // Take the input collected by the event loop since last frame
+            let mut frame = match raw_input.lock() {
+                Ok(mut raw) => raw.take_frame(),
+                Err(_) => input::InputFrame::default(),
+            };
+            frame.gamepad = gamepad_backend.poll();
+
+            // While replaying, the recorded input and time steps stand in for the live ones. Live
+            // input takes over after the last recorded frame, unless asked to quit after drawing it.
+            let mut replay_finished = false;
+            if let Some(replay) = player.as_mut() {
+                if let Some(recorded) = replay.next_frame() {
+                    frame = recorded.input.clone();
+                    delta_time = recorded.delta_time;
+                }
+                replay_finished = replay.is_finished();
+            }
+            if replay_finished {
+                println!("Replay finished");
+                player = None;
+            }
+            if let Some(recording) = recorder.as_mut() {
+                let recorded = replay::RecordedFrame { time: wall_time, delta_time, input: frame.clone() };
+                if let Err(e) = recording.record(&recorded) {
+                    println!("{}, recording stopped", e);
+                    recorder = None;
+                }
+            }
+            input.update(frame);
 
-                        // Yaw rotation
-                        VirtualKeyCode::Left=> {
-                            rotationYaw += 20.0 * delta_time;
-                        }
-                        VirtualKeyCode::Right => {
-                            rotationYaw -= 20.0 * delta_time;
-                        }
+            // pause with "P", step while paused with "N", slow down and speed up with "-" and "="
+            if input.pressed("pause") {
+                game_loop.toggle_pause();
+                println!("{}", if game_loop.is_paused() { "Paused" } else { "Running" });
+            }
+            if input.pressed("step_simulation") {
+                game_loop.step();
+            }
+            if input.pressed("slow_down") {
+                game_loop.scale_time(0.5);
+                println!("Time scale: {}", game_loop.time_scale);
+            }
+            if input.pressed("speed_up") {
+                game_loop.scale_time(2.0);
+                println!("Time scale: {}", game_loop.time_scale);
+            }
 
-                        // Pitch rotation
-                        VirtualKeyCode::Up=> {
-                            rotationPitch += 20.0 * delta_time;
-                        }
-                        VirtualKeyCode::Down => {
-                            rotationPitch -= 20.0 * delta_time;
-                        }
+            // save the scene as it is now with "F5"
+            if input.pressed("save_scene") {
+                scene_file.update_from(&terrain_node, &vao_names);
+                if let Err(e) = scene_file.save("./resources/scene.saved.ron") {
+                    println!("{}", e);
+                }
+            }
 
-                        // open the door
-                        VirtualKeyCode::O=> {
-                            moveDoorX = 0.1;
-                            moveDoorZ = 1.0;
-                        }
-                        // close the door
-                        VirtualKeyCode::C=> {
-                            moveDoorX = 0.0;
-                            moveDoorZ = 0.0;
-                        }
+            // camera movement along the view direction: (right, up, forward)
+            let movement = glm::vec3(input.axis("move_right"), input.axis("move_up"), input.axis("move_forward"));
 
-                        // default handler:
-                        _ => {}
+            // open doors with "O", close with "C"
+            let door_speed = if input.pressed("open_door") { 1.0 } else if input.pressed("close_door") { -1.0 } else { 0.0 };
+            if door_speed != 0.0 {
+                for (_, animators) in world.animators.iter_mut() {
+                    for door in animators.iter_mut().filter(|animator| animator.part == "Body/Door") {
+                        door.animator.set_speed(door_speed);
                     }
                 }
             }
-            // Handle mouse movement. delta contains the x and y movement of the mouse since last frame in pixels
-            if let Ok(mut delta) = mouse_delta.lock() {
-                // == // Optionally access the acumulated mouse movement between
-                // == // frames here with `delta.0` and `delta.1`
 
-                *delta = (0.0, 0.0); // reset when done
+            // grab or release the cursor with "G"
+            if input.pressed("grab_cursor") {
+                cursor_grabbed = set_cursor_grabbed(context.window(), !cursor_grabbed);
             }
 
-            
+            // switch camera with "1" to "4", frame the selected helicopter with "F"
+            if input.pressed("camera_fly") {
+                camera_mode = CameraMode::Fly;
+            }
+            if input.pressed("camera_orbit") && camera_mode != CameraMode::Orbit {
+                camera_mode = CameraMode::Orbit;
+                orbit_controller.pan_offset = glm::zero();
+                orbit_controller.align_to(&camera.pose);
+            }
+            if input.pressed("camera_chase") {
+                camera_mode = CameraMode::Chase;
+                chase_controller = camera_controller::ChaseController::chase();
+            }
+            if input.pressed("camera_cockpit") {
+                camera_mode = CameraMode::Chase;
+                chase_controller = camera_controller::ChaseController::cockpit();
+            }
+            if input.pressed("frame_selected") {
+                camera_mode = CameraMode::Orbit;
+                frame_selected = true;
+            }
 
-            // == // Please compute camera transforms here (exercise 2 & 3)
-            let mut view_projection_matrix;
-            unsafe {
-                let mut camTrans: glm::Mat4 =  glm::identity();
-                // matrix for camera transformations
-                camTrans = glm::rotation(rotationYaw.to_radians(), &glm::vec3(0.0, 1.0, 0.0)) * camTrans; // Yaw rotation
-                camTrans = glm::rotation(rotationPitch.to_radians(), &glm::vec3(1.0, 0.0, 0.0)) * camTrans; // Pitch rotation
-                camTrans = glm::translation(&glm::vec3(0.0 + motionX , 0.0 + motionY, 0.0 + motionZ)) * camTrans;
+            // cycle through the helicopters with "Tab"
+            if input.pressed("next_helicopter") {
+                selected_heli = selected_heli.map(|selected| (selected + 1) % heli_entities.len());
+                orbit_controller.pan_offset = glm::zero();
+                chase_controller.reset();
+            }
 
-                let projection : glm::Mat4 = glm::perspective(window_aspect_ratio, 60.0f32.to_radians(), 1.0, 1000.0);
+            // Handle mouse movement. The delta contains the x and y movement of the mouse since last frame in pixels
+            let mouse_delta = input.mouse_delta();
+            match camera_mode {
+                CameraMode::Fly => {
+                    fly_controller.translate(&mut camera.pose, &movement, delta_time);
+                    fly_controller.turn(&mut camera.pose, input.axis("turn_yaw"), input.axis("turn_pitch"), delta_time);
+                    // only look around while the cursor is grabbed, it would otherwise turn the
+                    // camera whenever the mouse crosses the window
+                    if cursor_grabbed {
+                        fly_controller.look(&mut camera.pose, mouse_delta);
+                    }
+                }
+                CameraMode::Orbit => {
+                    if input.held("orbit_rotate") {
+                        orbit_controller.rotate(mouse_delta);
+                    }
+                    if input.held("orbit_pan") {
+                        orbit_controller.pan(&camera.pose, mouse_delta);
+                    }
+                    orbit_controller.zoom(input.axis("zoom"));
+                }
+                CameraMode::Chase => {}
+            }
 
-                let transZ : glm::Mat4 = glm::translation(&glm::vec3(0.0, 0.0, -2.0));
+            // fly the player's helicopter, unless the same sticks and keys are flying the camera
+            let mut collective_input = 0.0;
+            if camera_mode != CameraMode::Fly {
+                collective_input           = input.axis("heli_collective");
+                heli_controls.cyclic_pitch = input.axis("heli_cyclic_pitch");
+                heli_controls.cyclic_roll  = input.axis("heli_cyclic_roll");
+                heli_controls.pedals       = input.axis("heli_pedals");
+            } else {
+                heli_controls.cyclic_pitch = 0.0;
+                heli_controls.cyclic_roll  = 0.0;
+                heli_controls.pedals       = 0.0;
+            }
 
-                view_projection_matrix = projection * camTrans * transZ;
-            };
+            // simulation, in as many fixed updates as this frame covers
+            let updates = game_loop.advance(delta_time);
+            for _ in 0..updates {
+                let timestep = game_loop.timestep();
+                game_loop.tick();
+                let time = game_loop.time();
+
+                heli_controls.move_collective(collective_input, timestep);
+                world.update(&ecs::UpdateContext {
+                    time,
+                    timestep,
+                    controls    : &heli_controls,
+                    ground      : &terrain_bvh,
+                    scene_root  : &terrain_node,
+                    mesh_bounds : &mesh_bounds,
+                });
+                interpolator.capture();
+            }
+            interpolator.apply(game_loop.alpha());
 
-            // animation
-            for n in 0..5 {
-                let mut heli_parent_node = &mut heli_all_parents[n];
-                
-                // position different helicopter in different place
-                let posDiff:f32 = (n*30) as f32;
-
-                // animated path
-                let animatedPath:Heading = toolbox::simple_heading_animation((elapsed-delta_time)*0.5);
-                heli_parent_node.position = glm::Vec3::new(posDiff + animatedPath.x,0.0,animatedPath.z);
-                heli_parent_node.rotation = glm::Vec3::new(animatedPath.pitch,animatedPath.yaw,animatedPath.roll);
-
-                // make rotors rotate
-                heli_parent_node.get_child(1).rotation = glm::Vec3::new(0.0,(elapsed-delta_time) * 720.0f32.to_radians(),0.0);
-                heli_parent_node.get_child(2).rotation = glm::Vec3::new((elapsed-delta_time) * 720.0f32.to_radians(),0.0,0.0);
-
-                // open doors with "O", close with "C"
-                heli_parent_node.get_child(0).get_child(0).position = glm::Vec3::new(moveDoorX,0.0,moveDoorZ);
-            }
-            // function to draw
-            unsafe fn draw_scene(node: &scene_graph::SceneNode, view_projection_matrix: &glm::Mat4, transformation_so_far: &glm::Mat4) {
-                let mut trans: glm::Mat4 = glm::identity();
-                // move to origin
-                trans = glm::translation(&glm::vec3(-node.reference_point.x,-node.reference_point.y,-node.reference_point.z,)) * trans;
-                // rotate
-                trans = glm::rotation(node.rotation.z, &glm::vec3(0.0, 0.0, 1.0)) * trans;
-                trans = glm::rotation(node.rotation.y, &glm::vec3(0.0, 1.0, 0.0)) * trans;
-                trans = glm::rotation(node.rotation.x, &glm::vec3(1.0, 0.0, 0.0)) * trans;
-                // move back
-                trans = glm::translation(&node.reference_point) * trans;
-                
-                
-                trans = glm::translation(&node.position) * trans;
-                trans = transformation_so_far * trans;
-                
-                let finalTrans : glm::Mat4 = view_projection_matrix * trans;
-                
-                if node.index_count>0 {
-                    gl::UniformMatrix4fv(0,1,gl::FALSE,finalTrans.as_ptr());
-                    gl::UniformMatrix4fv(1,1,gl::FALSE,trans.as_ptr());
-                    gl::BindVertexArray(node.vao_id);
-                    gl::DrawElements(gl::TRIANGLES, node.index_count, gl::UNSIGNED_INT, offset::<u32>(0));
+            // == // Please compute camera transforms here (exercise 2 & 3)
+            // follow the selected helicopter wherever the animation took it
+            let selected_entity = selected_heli.map(|selected| heli_entities[selected]);
+            let selected: *const SceneNode = selected_entity.and_then(|entity| world.transforms.get(entity))
+                .map_or(ptr::null(), |transform| transform.node);
+            if let Some(selected_trans) = terrain_node.transform_of(selected) {
+                match camera_mode {
+                    CameraMode::Fly => {}
+                    CameraMode::Orbit => {
+                        orbit_controller.target = (selected_trans * glm::vec4(0.0, 0.0, 0.0, 1.0)).xyz();
+                        if frame_selected {
+                            if let Some(collider) = selected_entity.and_then(|entity| world.colliders.get(entity)) {
+                                orbit_controller.frame(&collider.bounds, camera.fov_y);
+                            }
+                            frame_selected = false;
+                        }
+                        orbit_controller.apply(&mut camera.pose);
+                    }
+                    CameraMode::Chase => {
+                        chase_controller.update(&mut camera.pose, &selected_trans, delta_time);
+                    }
                 }
-                for &child in &node.children {
-                    draw_scene(&*child, view_projection_matrix,&trans);
+            }
+            let view_projection_matrix = camera.view_projection();
+            let position = camera.pose.position;
+            unsafe {
+                camera_buffer.update(&uniform_buffer::CameraBlock {
+                    view            : camera.view(),
+                    projection      : camera.projection(),
+                    view_projection : view_projection_matrix,
+                    camera_position : [position.x, position.y, position.z],
+                    time            : game_loop.time(),
+                });
+            }
+            // pick up edits to the shaders
+            match unsafe { simple_shader.reload_if_changed() } {
+                Ok(true) => println!("Reloaded shaders"),
+                Ok(false) => {}
+                Err(e) => println!("{}\nKeeping the previous shaders", e),
+            }
+            match unsafe { terrain_shader.reload_if_changed() } {
+                Ok(true) => {
+                    println!("Reloaded terrain shaders");
+                    unsafe { terrain_detail.apply(&terrain_shader) };
                 }
-                
+                Ok(false) => {}
+                Err(e) => println!("{}\nKeeping the previous terrain shaders", e),
             }
 
+            // sort the scene into one batch per mesh and material to draw
+            renderer.collect(&terrain_node);
+
             unsafe {
                 // Clear the color and depth buffers
                 gl::ClearColor(0.035, 0.046, 0.078, 1.0); // night sky, full opacity
@@ -454,11 +722,18 @@ fn main() {
                 // gl::BindVertexArray(tail_rotor_vao);
                 // gl::DrawElements(gl::TRIANGLES, heli_mesh.tail_rotor.index_count, gl::UNSIGNED_INT, offset::<u32>(0));
 
-                draw_scene(&terrain_node, &view_projection_matrix, &glm::identity());
+                simple_shader.activate();
+                renderer.draw();
+                terrain_shader.activate();
+                renderer.draw_patches();
             }
 
             // Display the new color buffer on the display
             context.swap_buffers().unwrap(); // we use "double buffering" to avoid artifacts
+
+            if replay_finished && replay_options.quit_after_replay {
+                std::process::exit(0);
+            }
         }
     });
 
@@ -522,31 +797,38 @@ fn main() {
                     },
                 ..
             } => {
-                if let Ok(mut keys) = arc_pressed_keys.lock() {
-                    match key_state {
-                        Released => {
-                            if keys.contains(&keycode) {
-                                let i = keys.iter().position(|&k| k == keycode).unwrap();
-                                keys.remove(i);
-                            }
-                        }
-                        Pressed => {
-                            if !keys.contains(&keycode) {
-                                keys.push(keycode);
-                            }
-                        }
-                    }
+                if let Ok(mut raw) = arc_raw_input.lock() {
+                    raw.handle_key(keycode, key_state);
                 }
 
-                // Handle Escape and Q keys separately
-                match keycode {
-                    Escape => {
-                        *control_flow = ControlFlow::Exit;
-                    }
-                    Q => {
-                        *control_flow = ControlFlow::Exit;
-                    }
-                    _ => {}
+                // Handle the quit keys (Escape and Q by default) separately
+                if key_state == Pressed && quit_bindings.is_bound("quit", input::Binding::Key(keycode)) {
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            // Keep track of currently pressed mouse buttons
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                ..
+            } => {
+                if let Ok(mut raw) = arc_raw_input.lock() {
+                    raw.handle_button(button, state);
+                }
+                if state == Pressed && quit_bindings.is_bound("quit", input::Binding::Mouse(button)) {
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            Event::WindowEvent {
+                event: WindowEvent::MouseWheel { delta, .. },
+                ..
+            } => {
+                // Accumulate scrolling, touchpads report pixels rather than lines
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 20.0,
+                };
+                if let Ok(mut raw) = arc_raw_input.lock() {
+                    raw.add_scroll(lines);
                 }
             }
             Event::DeviceEvent {
@@ -554,8 +836,8 @@ fn main() {
                 ..
             } => {
                 // Accumulate mouse movement
-                if let Ok(mut position) = arc_mouse_delta.lock() {
-                    *position = (position.0 + delta.0 as f32, position.1 + delta.1 as f32);
+                if let Ok(mut raw) = arc_raw_input.lock() {
+                    raw.add_mouse_motion(delta.0 as f32, delta.1 as f32);
                 }
             }
             _ => {}
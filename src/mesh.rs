@@ -7,6 +7,7 @@ fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
 
 // Mesh
 
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices    : Vec<f32>,
     pub normals     : Vec<f32>,
@@ -27,6 +28,22 @@ impl Mesh {
             index_count,
         }
     }
+
+    // Every object in an obj file by name, painted white
+    pub fn load_objects(path: &str) -> Result<Vec<(String, Mesh)>, String> {
+        let (models, _materials)
+            = tobj::load_obj(path,
+                &tobj::LoadOptions{
+                    triangulate: true,
+                    single_index: true,
+                    ..Default::default()
+                }
+            ).map_err(|e| format!("Failed to load model {}: {}", path, e))?;
+        for model in &models {
+            println!("Loaded {} with {} points and {} triangles.", model.name, model.mesh.positions.len() / 3, model.mesh.indices.len() / 3);
+        }
+        Ok(models.into_iter().map(|model| (model.name, Mesh::from(model.mesh, [1.0, 1.0, 1.0, 1.0]))).collect())
+    }
 }
 
 // Lunar terrain
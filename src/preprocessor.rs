@@ -0,0 +1,275 @@
+use crate::shader::{modified, ShaderError};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// A small preprocessor run over shader sources before they are compiled.
+//
+// `#include "file"` is replaced by the file, found relative to the directory of the file including
+// it. Every file is included at most once, however many files include it, so shared code needs no
+// include guards of its own, and a file that ends up including itself is an error. `#define`s
+// given from Rust are put right after the `#version` line, to build variants of one source.
+//
+// The driver reports errors by line of the source it was given, so the lines of the result are
+// mapped back to the files and lines they came from.
+
+pub struct Preprocessed {
+    pub source : String,
+    pub files  : Vec<(String, Option<SystemTime>)>, // every file read, and when it was modified before reading it
+    names      : Vec<String>,                       // what `lines` refer to
+    lines      : Vec<(usize, u32)>,                 // for each line of `source`, the name and line it came from
+}
+
+impl Preprocessed {
+    // The file and line that line `line` of the result, counting from 1, came from
+    pub fn original_line(&self, line: u32) -> Option<(&str, u32)> {
+        let &(name, original) = self.lines.get((line as usize).checked_sub(1)?)?;
+        Some((&self.names[name], original))
+    }
+}
+
+struct Preprocessor<'a> {
+    defines : &'a [(String, String)],
+    result  : Preprocessed,
+    stack   : Vec<PathBuf>, // the files being included, outermost first
+    seen    : Vec<PathBuf>, // every file included so far
+    defined : bool,         // whether the defines are in yet
+}
+
+// Preprocess the shader in the file at `path`
+pub fn preprocess_file(path: &str, defines: &[(String, String)]) -> Result<Preprocessed, ShaderError> {
+    let modified_before = modified(path);
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| ShaderError::Read { path: path.to_string(), error: e.to_string() })?;
+    let mut preprocessor = Preprocessor::new(defines);
+    preprocessor.result.files.push((path.to_string(), modified_before));
+    let directory = Path::new(path).parent().unwrap_or(Path::new("."));
+    preprocessor.run(&source, path, directory, Some(canonical(Path::new(path))), 0)?;
+    Ok(preprocessor.result)
+}
+
+// Preprocess a shader not read from a file, named `name` in errors. It includes files relative to `directory`.
+pub fn preprocess_source(source: &str, name: &str, directory: &Path, defines: &[(String, String)]) -> Result<Preprocessed, ShaderError> {
+    let mut preprocessor = Preprocessor::new(defines);
+    preprocessor.run(source, name, directory, None, 0)?;
+    Ok(preprocessor.result)
+}
+
+// Files are told apart by their canonical path, so "a/../b.glsl" and "b.glsl" are the same one
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The file name in `#include "file"`, if `line` is an include
+fn include_target(line: &str) -> Option<Result<&str, String>> {
+    let rest = line.trim_start().strip_prefix('#')?.trim_start().strip_prefix("include")?;
+    let rest = rest.trim();
+    Some(match rest.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+        Some(target) if !target.is_empty() => Ok(target),
+        _ => Err(format!("expected #include \"file\", found #include {}", rest)),
+    })
+}
+
+impl<'a> Preprocessor<'a> {
+    fn new(defines: &'a [(String, String)]) -> Self {
+        Preprocessor {
+            defines,
+            result: Preprocessed { source: String::new(), files: vec![], names: vec![], lines: vec![] },
+            stack: vec![],
+            seen: vec![],
+            defined: false,
+        }
+    }
+
+    fn push_line(&mut self, text: &str, name: usize, line: u32) {
+        self.result.source.push_str(text);
+        self.result.source.push('\n');
+        self.result.lines.push((name, line));
+    }
+
+    // Add `source` to the result. `depth` counts the includes it is inside of.
+    fn run(&mut self, source: &str, name: &str, directory: &Path, file: Option<PathBuf>, depth: usize) -> Result<(), ShaderError> {
+        let top_level = depth == 0;
+        if let Some(file) = &file {
+            self.stack.push(file.clone());
+            self.seen.push(file.clone());
+        }
+        self.result.names.push(name.to_string());
+        let name_index = self.result.names.len() - 1;
+
+        for (i, text) in source.lines().enumerate() {
+            let line = i as u32 + 1;
+            let include = match include_target(text) {
+                Some(Ok(include)) => include,
+                Some(Err(message)) => return Err(ShaderError::Include { path: name.to_string(), line, message }),
+                None => {
+                    self.push_line(text, name_index, line);
+                    // the defines go straight after the version, which must come first
+                    if top_level && text.trim_start().starts_with("#version") {
+                        self.push_defines(name_index, line);
+                    }
+                    continue;
+                }
+            };
+
+            let include_path = directory.join(include);
+            let include_file = canonical(&include_path);
+            if self.stack.contains(&include_file) {
+                let mut chain: Vec<String> = self.stack.iter().map(|file| file.display().to_string()).collect();
+                chain.push(include_file.display().to_string());
+                let message = format!("include cycle {}", chain.join(" -> "));
+                return Err(ShaderError::Include { path: name.to_string(), line, message });
+            }
+            if self.seen.contains(&include_file) {
+                // already in, keep the line numbers the same
+                self.push_line("", name_index, line);
+                continue;
+            }
+
+            let include_name = include_path.to_string_lossy().into_owned();
+            let modified_before = modified(&include_name);
+            let include_source = std::fs::read_to_string(&include_path).map_err(|e| ShaderError::Include {
+                path: name.to_string(),
+                line,
+                message: format!("failed to read {}: {}", include_name, e),
+            })?;
+            self.result.files.push((include_name.clone(), modified_before));
+            let include_directory = include_path.parent().unwrap_or(Path::new(".")).to_path_buf();
+            self.run(&include_source, &include_name, &include_directory, Some(include_file), depth + 1)?;
+        }
+
+        // without a version, the defines can go first
+        if top_level && !self.defined {
+            let source = std::mem::take(&mut self.result.source);
+            let lines = std::mem::take(&mut self.result.lines);
+            self.push_defines(name_index, 0);
+            self.result.source.push_str(&source);
+            self.result.lines.extend(lines);
+        }
+        if file.is_some() {
+            self.stack.pop();
+        }
+        Ok(())
+    }
+
+    fn push_defines(&mut self, name: usize, line: u32) {
+        self.defined = true;
+        for (define, value) in self.defines {
+            self.push_line(&format!("#define {} {}", define, value), name, line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory holding `files`, as (name, contents)
+    fn directory(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("gloom-rs-preprocessor-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        for (name, contents) in files {
+            let path = directory.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        directory
+    }
+
+    fn preprocess(directory: &Path, file: &str) -> Result<Preprocessed, ShaderError> {
+        preprocess_file(directory.join(file).to_str().unwrap(), &[])
+    }
+
+    fn lines(preprocessed: &Preprocessed) -> Vec<&str> {
+        preprocessed.source.lines().collect()
+    }
+
+    #[test]
+    fn nested_includes_are_expanded_in_place() {
+        let directory = directory("nested", &[
+            ("main.frag", "#version 430\n#include \"lib/a.glsl\"\nvoid main() {}"),
+            ("lib/a.glsl", "// a\n#include \"b.glsl\"\n// a again"),
+            ("lib/b.glsl", "// b"),
+        ]);
+
+        let preprocessed = preprocess(&directory, "main.frag").unwrap();
+        assert_eq!(lines(&preprocessed), ["#version 430", "// a", "// b", "// a again", "void main() {}"]);
+        assert_eq!(preprocessed.files.len(), 3);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn repeated_includes_are_only_expanded_once() {
+        let directory = directory("repeated", &[
+            ("main.frag", "#version 430\n#include \"a.glsl\"\n#include \"common.glsl\"\nvoid main() {}"),
+            ("a.glsl", "#include \"common.glsl\"\n// a"),
+            ("common.glsl", "// common"),
+        ]);
+
+        let preprocessed = preprocess(&directory, "main.frag").unwrap();
+        assert_eq!(lines(&preprocessed), ["#version 430", "// common", "// a", "", "void main() {}"]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let directory = directory("cycle", &[
+            ("main.frag", "#version 430\n#include \"a.glsl\""),
+            ("a.glsl", "#include \"b.glsl\""),
+            ("b.glsl", "// b\n#include \"a.glsl\""),
+        ]);
+
+        match preprocess(&directory, "main.frag") {
+            Err(ShaderError::Include { path, line, message }) => {
+                assert!(path.ends_with("b.glsl"), "{}", path);
+                assert_eq!(line, 2);
+                assert!(message.starts_with("include cycle"), "{}", message);
+            }
+            Err(e) => panic!("expected an include cycle, got {}", e),
+            Ok(_) => panic!("expected an include cycle"),
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn missing_includes_are_errors() {
+        let directory = directory("missing", &[
+            ("main.frag", "#version 430\n\n#include \"missing.glsl\""),
+        ]);
+
+        match preprocess(&directory, "main.frag") {
+            Err(ShaderError::Include { path, line, message }) => {
+                assert!(path.ends_with("main.frag"), "{}", path);
+                assert_eq!(line, 3);
+                assert!(message.contains("missing.glsl"), "{}", message);
+            }
+            Err(e) => panic!("expected a missing include, got {}", e),
+            Ok(_) => panic!("expected a missing include"),
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn lines_map_back_across_includes() {
+        let directory = directory("lines", &[
+            ("main.frag", "#version 430\n#include \"a.glsl\"\nvoid main() {\n    error;\n}"),
+            ("a.glsl", "// a\n// a again"),
+        ]);
+        let defines = [("LIGHTS".to_string(), "4".to_string())];
+        let main = directory.join("main.frag");
+        let main = main.to_str().unwrap();
+
+        let preprocessed = preprocess_file(main, &defines).unwrap();
+        assert_eq!(lines(&preprocessed)[..4], ["#version 430", "#define LIGHTS 4", "// a", "// a again"]);
+        assert_eq!(preprocessed.original_line(1), Some((main, 1)));
+        let (name, line) = preprocessed.original_line(4).unwrap();
+        assert!(name.ends_with("a.glsl"), "{}", name);
+        assert_eq!(line, 2);
+        // line 4 of main.frag, after the define and the two included lines
+        assert_eq!(preprocessed.source.lines().nth(5), Some("    error;"));
+        assert_eq!(preprocessed.original_line(6), Some((main, 4)));
+        assert_eq!(preprocessed.original_line(0), None);
+        assert_eq!(preprocessed.original_line(100), None);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
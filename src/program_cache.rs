@@ -0,0 +1,190 @@
+use crate::shader::ShaderType;
+use crate::util;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+// Linked programs kept on disk, so that later launches can skip compiling their shaders.
+//
+// A program is stored under a hash of the preprocessed sources of its stages and of the driver
+// that built it, since a binary only works with the driver it came from. The driver is also
+// written into the file and compared on loading, in case two keys ever collide. Whatever goes
+// wrong, from a missing file to a driver that refuses its own binary after an update, the shaders
+// are simply compiled from source again.
+
+const MAGIC : &[u8; 4] = b"GLPB";
+
+// What the driver is called, as part of the key
+pub unsafe fn driver() -> String {
+    format!("{} / {} / {}",
+        util::get_gl_string(gl::VENDOR),
+        util::get_gl_string(gl::RENDERER),
+        util::get_gl_string(gl::VERSION),
+    )
+}
+
+// Whether the driver can hand out program binaries at all
+pub unsafe fn supported() -> bool {
+    !formats().is_empty()
+}
+
+unsafe fn formats() -> Vec<u32> {
+    let mut count = 0;
+    gl::GetIntegerv(gl::NUM_PROGRAM_BINARY_FORMATS, &mut count);
+    if count <= 0 {
+        return vec![];
+    }
+    let mut formats = vec![0; count as usize];
+    gl::GetIntegerv(gl::PROGRAM_BINARY_FORMATS, formats.as_mut_ptr());
+    formats.into_iter().map(|format| format as u32).collect()
+}
+
+// 64 bit FNV-1a, which unlike `DefaultHasher` is the same from one build to the next
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3))
+}
+
+// The key of a program built by `driver` from the given stages, in the order they are attached
+pub fn key<'a>(driver: &str, stages: impl Iterator<Item = (ShaderType, &'a str)>) -> u64 {
+    let mut hash = fnv1a(0xcbf2_9ce4_8422_2325, driver.as_bytes());
+    for (shader_type, source) in stages {
+        // the separators keep "ab" + "c" apart from "a" + "bc"
+        hash = fnv1a(hash, format!("\0{:?}\0", shader_type).as_bytes());
+        hash = fnv1a(hash, source.as_bytes());
+    }
+    hash
+}
+
+fn path(directory: &Path, key: u64) -> PathBuf {
+    directory.join(format!("{:016x}.bin", key))
+}
+
+// Load the binary stored under `key` into `program_id`, returning whether it is now linked
+pub unsafe fn load(directory: &Path, key: u64, driver: &str, program_id: u32) -> bool {
+    let path = path(directory, key);
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+    let (format, binary) = match parse(&bytes, driver) {
+        Some(parsed) => parsed,
+        None => {
+            println!("Ignoring cached program {}, it was made by another driver", path.display());
+            return false;
+        }
+    };
+    // the driver raises an error on formats it doesn't know, rather than just failing to link
+    if !formats().contains(&format) {
+        println!("Ignoring cached program {}, the driver no longer takes its format", path.display());
+        return false;
+    }
+    gl::ProgramBinary(program_id, format, binary.as_ptr() as *const _, binary.len() as i32);
+    let mut success = i32::from(gl::FALSE);
+    gl::GetProgramiv(program_id, gl::LINK_STATUS, &mut success);
+    if success != i32::from(gl::TRUE) {
+        println!("Ignoring cached program {}, the driver rejected it", path.display());
+        return false;
+    }
+    true
+}
+
+// A cache file holding `binary` of `format`, written by `driver`
+fn contents(format: u32, driver: &str, binary: &[u8]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&format.to_le_bytes());
+    bytes.extend_from_slice(&(driver.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(driver.as_bytes());
+    bytes.extend_from_slice(binary);
+    bytes
+}
+
+// The format and the binary in a cache file, if it was written by `driver`
+fn parse<'a>(bytes: &'a [u8], driver: &str) -> Option<(u32, &'a [u8])> {
+    let rest = bytes.strip_prefix(MAGIC)?;
+    let format = u32::from_le_bytes(rest.get(0..4)?.try_into().ok()?);
+    let driver_length = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?) as usize;
+    let written_by = rest.get(8..8 + driver_length)?;
+    if written_by != driver.as_bytes() {
+        return None;
+    }
+    Some((format, &rest[8 + driver_length..]))
+}
+
+// Store the binary of the linked `program_id` under `key`. A program linked without
+// `PROGRAM_BINARY_RETRIEVABLE_HINT` may not have one.
+pub unsafe fn save(directory: &Path, key: u64, driver: &str, program_id: u32) -> Result<(), String> {
+    let mut length = 0;
+    gl::GetProgramiv(program_id, gl::PROGRAM_BINARY_LENGTH, &mut length);
+    if length <= 0 {
+        return Err("the driver gave no binary".to_string());
+    }
+    let mut binary = vec![0u8; length as usize];
+    let mut written = 0;
+    let mut format = 0;
+    gl::GetProgramBinary(program_id, length, &mut written, &mut format, binary.as_mut_ptr() as *mut _);
+    binary.truncate(written.max(0) as usize);
+
+    let path = path(directory, key);
+    std::fs::create_dir_all(directory)
+        .and_then(|_| std::fs::write(&path, contents(format, driver, &binary)))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRIVER: &str = "Vendor / Renderer / 4.6.0 Driver 1.2";
+
+    #[test]
+    fn files_parse_back() {
+        let bytes = contents(0x8e21, DRIVER, &[1, 2, 3, 4, 5]);
+        assert_eq!(parse(&bytes, DRIVER), Some((0x8e21, &[1u8, 2, 3, 4, 5][..])));
+        assert_eq!(parse(&contents(7, DRIVER, &[]), DRIVER), Some((7, &[][..])));
+    }
+
+    #[test]
+    fn files_from_other_drivers_are_refused() {
+        let bytes = contents(0x8e21, DRIVER, &[1, 2, 3]);
+        assert_eq!(parse(&bytes, "Vendor / Renderer / 4.6.0 Driver 1.3"), None);
+        assert_eq!(parse(&bytes, "Vendor / Renderer / 4.6.0 Driver 1."), None);
+        assert_eq!(parse(&bytes, ""), None);
+    }
+
+    #[test]
+    fn broken_files_are_refused() {
+        let bytes = contents(0x8e21, DRIVER, &[1, 2, 3]);
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(parse(&bad_magic, DRIVER), None);
+        assert_eq!(parse(b"", DRIVER), None);
+        // cut off in the format, in the driver length and in the driver
+        for length in [2, 6, 10, 8 + 4 + DRIVER.len() - 1] {
+            assert_eq!(parse(&bytes[..length], DRIVER), None, "{} bytes", length);
+        }
+        // a driver length past the end of the file
+        let mut long_driver = bytes;
+        long_driver[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(parse(&long_driver, DRIVER), None);
+    }
+
+    #[test]
+    fn keys_tell_programs_apart() {
+        let key_of = |driver: &str, stages: &[(ShaderType, &str)]| key(driver, stages.iter().copied());
+        let program = [(ShaderType::Vertex, "void main() {}"), (ShaderType::Fragment, "void main() { }")];
+        assert_eq!(key_of(DRIVER, &program), key_of(DRIVER, &program));
+
+        let others = [
+            key_of("another driver", &program),
+            key_of(DRIVER, &[program[0]]),
+            key_of(DRIVER, &[program[1], program[0]]),
+            key_of(DRIVER, &[(ShaderType::Vertex, "void main() {} "), program[1]]),
+            key_of(DRIVER, &[(ShaderType::Geometry, program[0].1), program[1]]),
+            // the same text split between the stages differently
+            key_of(DRIVER, &[(ShaderType::Vertex, "void main() {}void"), (ShaderType::Fragment, " main() { }")]),
+        ];
+        for (i, other) in others.iter().enumerate() {
+            assert_ne!(*other, key_of(DRIVER, &program), "{}", i);
+        }
+        assert_eq!(path(Path::new("cache"), 0xab), Path::new("cache/00000000000000ab.bin"));
+    }
+}
@@ -0,0 +1,177 @@
+extern crate nalgebra_glm as glm;
+
+use crate::scene_graph::SceneNode;
+use std::collections::HashMap;
+use std::{mem, ptr};
+
+// Instanced drawing of the scene graph.
+//
+// Every frame the scene is collected into batches, one per VAO, and nodes drawing the same mesh
+// share its VAO whatever their material. A batch is drawn with a single `DrawElementsInstanced`,
+// each node in it being one instance with its own model matrix and material color in an instance
+// buffer, so a scene with hundreds of helicopters costs one draw call per part rather than one per
+// part per helicopter.
+//
+// VAOs given a patch size are drawn as patches for the tessellation stages instead of as
+// triangles, by `draw_patches` rather than `draw`, since a program either has those stages or not.
+
+// Vertex attribute locations of the per-instance data, after position, color and normal
+const INSTANCE_MODEL_LOCATION : u32 = 3; // a mat4 takes this and the next three
+const INSTANCE_COLOR_LOCATION : u32 = 7;
+
+// What each instance gets in the instance buffer
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Instance {
+    pub model : glm::Mat4, // model to world space
+    pub color : glm::Vec4, // multiplied with the vertex colors
+}
+
+pub struct Batch {
+    pub vao_id      : u32,
+    pub index_count : i32,
+    pub patch_size  : Option<i32>, // vertices per patch, if drawn as patches
+    pub instances   : Vec<Instance>,
+}
+
+// The buffer holding a VAO's instances, grown as needed
+struct InstanceBuffer {
+    buffer_id : u32,
+    capacity  : usize, // instances
+}
+
+pub struct Renderer {
+    batches          : Vec<Batch>,              // in the order their VAOs were first met
+    batch_of_vao     : HashMap<u32, usize>,
+    instance_buffers : HashMap<u32, InstanceBuffer>,
+    patch_sizes      : HashMap<u32, i32>,       // of the VAOs drawn as patches
+    pub draw_calls   : usize,                   // since the last `draw`
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Renderer {
+            batches          : vec![],
+            batch_of_vao     : HashMap::new(),
+            instance_buffers : HashMap::new(),
+            patch_sizes      : HashMap::new(),
+            draw_calls       : 0,
+        }
+    }
+
+    // Draw the VAO as patches of `vertices` vertices each from now on
+    pub fn set_patch_size(&mut self, vao_id: u32, vertices: i32) {
+        self.patch_sizes.insert(vao_id, vertices);
+        if let Some(&index) = self.batch_of_vao.get(&vao_id) {
+            self.batches[index].patch_size = Some(vertices);
+        }
+    }
+
+    // Sort every node below and including `root` into the batch of its VAO, with its transformation
+    // into world space. The batches keep their allocations from one frame to the next.
+    pub fn collect(&mut self, root: &SceneNode) {
+        for batch in &mut self.batches {
+            batch.instances.clear();
+        }
+        self.collect_node(root, &glm::identity());
+    }
+
+    fn collect_node(&mut self, node: &SceneNode, transformation_so_far: &glm::Mat4) {
+        let trans = transformation_so_far * node.local_transform();
+        if node.index_count > 0 {
+            let index = match self.batch_of_vao.get(&node.vao_id) {
+                Some(&index) => index,
+                None => {
+                    let patch_size = self.patch_sizes.get(&node.vao_id).copied();
+                    self.batches.push(Batch { vao_id: node.vao_id, index_count: node.index_count, patch_size, instances: vec![] });
+                    self.batch_of_vao.insert(node.vao_id, self.batches.len() - 1);
+                    self.batches.len() - 1
+                }
+            };
+            self.batches[index].instances.push(Instance { model: trans, color: node.color });
+        }
+        for child in node.children() {
+            self.collect_node(child, &trans);
+        }
+    }
+
+    pub fn batches(&self) -> &[Batch] {
+        &self.batches
+    }
+
+    // Draw the triangles of what was collected last with the active program. It reads the camera
+    // from the uniform buffers of `src/uniform_buffer.rs` and the instance data from attributes 3 to 7.
+    pub unsafe fn draw(&mut self) {
+        self.draw_calls = 0;
+        self.draw_batches(false);
+    }
+
+    // Draw the patches of what was collected last with the active program, which must have
+    // tessellation stages taking patches of the sizes given
+    pub unsafe fn draw_patches(&mut self) {
+        self.draw_batches(true);
+    }
+
+    unsafe fn draw_batches(&mut self, patches: bool) {
+        for batch in &self.batches {
+            if batch.instances.is_empty() || batch.patch_size.is_some() != patches {
+                continue;
+            }
+            gl::BindVertexArray(batch.vao_id);
+            let buffer = self.instance_buffers.entry(batch.vao_id).or_insert_with(|| InstanceBuffer::create());
+            buffer.upload(&batch.instances);
+            let mode = match batch.patch_size {
+                Some(vertices) => {
+                    gl::PatchParameteri(gl::PATCH_VERTICES, vertices);
+                    gl::PATCHES
+                }
+                None => gl::TRIANGLES,
+            };
+            gl::DrawElementsInstanced(
+                mode,
+                batch.index_count,
+                gl::UNSIGNED_INT,
+                ptr::null(),
+                batch.instances.len() as i32,
+            );
+            self.draw_calls += 1;
+        }
+        gl::BindVertexArray(0);
+    }
+}
+
+impl InstanceBuffer {
+    // A buffer for the VAO currently bound, feeding it the instance attributes
+    unsafe fn create() -> Self {
+        let mut buffer_id: u32 = 0;
+        gl::GenBuffers(1, &mut buffer_id);
+        gl::BindBuffer(gl::ARRAY_BUFFER, buffer_id);
+
+        let stride = mem::size_of::<Instance>() as i32;
+        let column_size = mem::size_of::<glm::Vec4>();
+        for column in 0..4 {
+            let location = INSTANCE_MODEL_LOCATION + column as u32;
+            gl::VertexAttribPointer(location, 4, gl::FLOAT, gl::FALSE, stride, (column * column_size) as *const _);
+            gl::EnableVertexAttribArray(location);
+            gl::VertexAttribDivisor(location, 1);
+        }
+        let color_offset = mem::size_of::<glm::Mat4>();
+        gl::VertexAttribPointer(INSTANCE_COLOR_LOCATION, 4, gl::FLOAT, gl::FALSE, stride, color_offset as *const _);
+        gl::EnableVertexAttribArray(INSTANCE_COLOR_LOCATION);
+        gl::VertexAttribDivisor(INSTANCE_COLOR_LOCATION, 1);
+
+        InstanceBuffer { buffer_id, capacity: 0 }
+    }
+
+    // Replace the buffer's contents with `instances`, growing it first if they don't fit
+    unsafe fn upload(&mut self, instances: &[Instance]) {
+        gl::BindBuffer(gl::ARRAY_BUFFER, self.buffer_id);
+        let size = mem::size_of_val(instances) as isize;
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().next_power_of_two();
+            let capacity_size = (self.capacity * mem::size_of::<Instance>()) as isize;
+            gl::BufferData(gl::ARRAY_BUFFER, capacity_size, ptr::null(), gl::DYNAMIC_DRAW);
+        }
+        gl::BufferSubData(gl::ARRAY_BUFFER, 0, size, instances.as_ptr() as *const _);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::input::InputFrame;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+// Recording and replaying input, frame by frame.
+//
+// A recording is a text file with one frame per line: when it happened, how much time the frame
+// simulated, and the input it saw. Frames are written as they happen, so the recording survives
+// the program being closed at any point. Replaying feeds the frames back in order with their
+// recorded time steps instead of the wall clock, which reproduces the session exactly.
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub time       : f32, // wall clock seconds since the recording started
+    pub delta_time : f32, // seconds simulated by this frame
+    pub input      : InputFrame,
+}
+
+pub struct Recorder {
+    writer : BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> Result<Recorder, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create input recording {}: {}", path, e))?;
+        println!("Recording input to {}", path);
+        Ok(Recorder { writer: BufWriter::new(file) })
+    }
+
+    pub fn record(&mut self, frame: &RecordedFrame) -> Result<(), String> {
+        let line = ron::to_string(frame)
+            .map_err(|e| format!("Failed to serialize input frame: {}", e))?;
+        writeln!(self.writer, "{}", line)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("Failed to write input frame: {}", e))
+    }
+}
+
+pub struct Player {
+    frames : Vec<RecordedFrame>,
+    next   : usize,
+}
+
+impl Player {
+    pub fn load(path: &str) -> Result<Player, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open input recording {}: {}", path, e))?;
+        let mut frames = vec![];
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("Failed to read input recording {}: {}", path, e))?;
+            if line.trim().is_empty() { continue }
+            let frame = ron::from_str(&line)
+                .map_err(|e| format!("{}:{}: Failed to parse input frame: {}", path, i + 1, e))?;
+            frames.push(frame);
+        }
+        println!("Replaying {} frames of input from {}", frames.len(), path);
+        Ok(Player { frames, next: 0 })
+    }
+
+    pub fn from_frames(frames: Vec<RecordedFrame>) -> Player {
+        Player { frames, next: 0 }
+    }
+
+    // The next frame to play back, or None once the recording is over
+    pub fn next_frame(&mut self) -> Option<&RecordedFrame> {
+        let frame = self.frames.get(self.next);
+        if frame.is_some() {
+            self.next += 1;
+        }
+        frame
+    }
+
+    // Whether every frame has been played back
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.frames.len()
+    }
+}
+
+// Command line options controlling recording and replay:
+//   --record <file>          write every frame of input to <file>
+//   --replay <file>          play the input from <file> back instead of the live input
+//   --fixed-timestep <hz>    simulate frames of exactly 1/<hz> seconds instead of the wall clock
+//   --quit-after-replay      exit once the replay is over, rather than handing over to live input
+#[derive(Clone, Debug, Default)]
+pub struct ReplayOptions {
+    pub record            : Option<String>,
+    pub replay            : Option<String>,
+    pub fixed_timestep    : Option<f32>,
+    pub quit_after_replay : bool,
+}
+
+impl ReplayOptions {
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<ReplayOptions, String> {
+        let mut options = ReplayOptions::default();
+        let mut args = args.skip(1); // the program name
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("Missing value for {}", name));
+            match arg.as_str() {
+                "--record" => options.record = Some(value("--record")?),
+                "--replay" => options.replay = Some(value("--replay")?),
+                "--fixed-timestep" => {
+                    let hz: f32 = value("--fixed-timestep")?.parse()
+                        .map_err(|e| format!("Invalid value for --fixed-timestep: {}", e))?;
+                    if hz <= 0.0 {
+                        return Err("--fixed-timestep must be positive".to_string());
+                    }
+                    options.fixed_timestep = Some(1.0 / hz);
+                }
+                "--quit-after-replay" => options.quit_after_replay = true,
+                other => return Err(format!("Unknown argument: {}", other)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gamepad::{GamepadAxis, GamepadButton, GamepadState};
+    use glutin::event::{MouseButton, VirtualKeyCode};
+
+    fn frames() -> Vec<RecordedFrame> {
+        (0..20).map(|i| {
+            let i = i as f32;
+            let mut input = InputFrame::default();
+            if i as usize % 3 == 0 {
+                input.keys_pressed.push(VirtualKeyCode::W);
+            }
+            input.keys_held.push(VirtualKeyCode::W);
+            input.buttons_held.push(MouseButton::Left);
+            input.mouse_delta = (i * 0.37, -i / 7.0);
+            input.scroll = if i as usize % 5 == 0 { 1.0 } else { 0.0 };
+            input.gamepad = GamepadState::default();
+            input.gamepad.set_pressed(GamepadButton::South, i as usize % 2 == 0);
+            input.gamepad.set_value(GamepadAxis::LeftStickX, (i * 0.1).sin());
+            RecordedFrame { time: i / 59.3, delta_time: 1.0 / 60.0 + i * 1e-4, input }
+        }).collect()
+    }
+
+    #[test]
+    fn recordings_play_back_the_same_frames() {
+        let path = std::env::temp_dir().join(format!("gloom-rs-replay-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut recorder = Recorder::create(path).unwrap();
+        for frame in frames() {
+            recorder.record(&frame).unwrap();
+        }
+        drop(recorder);
+
+        let mut loaded = Player::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut expected = Player::from_frames(frames());
+        while let Some(frame) = expected.next_frame() {
+            assert!(!loaded.is_finished());
+            assert_eq!(loaded.next_frame(), Some(frame));
+        }
+        assert!(loaded.is_finished());
+        assert_eq!(loaded.next_frame(), None);
+    }
+
+    #[test]
+    fn empty_replays_are_finished_at_once() {
+        let mut player = Player::from_frames(vec![]);
+        assert!(player.is_finished());
+        assert_eq!(player.next_frame(), None);
+    }
+}
@@ -0,0 +1,793 @@
+extern crate nalgebra_glm as glm;
+
+use crate::animation::{Clip, Interpolation, LoopMode, Property, Track};
+use crate::mesh::Mesh;
+use crate::scene_graph::{Node, SceneNode};
+use crate::terrain;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Scene files: the whole scene in one human-editable RON file, loaded at startup.
+//
+// A scene file lists the meshes to load, the materials to paint them with, lights, cameras and
+// animation clips, and then the tree of nodes using them. Nodes may also say what moves them and
+// which of their parts are animated, which the program turns into entities (see `src/ecs.rs`).
+// Angles are written in degrees. Everything but the node tree may be left out.
+//
+// A subtree used in many places is written once as a prefab. A node naming a prefab becomes a copy
+// of it placed where the node says, sharing its meshes, and can change any of its parts.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MeshDescription {
+    pub name       : String,
+    pub file       : String,         // an obj file
+    #[serde(default)]
+    pub object     : Option<String>, // which object in the file, if it holds more than one
+    #[serde(default)]
+    pub patch_size : Option<i32>,    // drawn as patches of this many vertices to tessellate rather than as triangles, only 3 for now
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Material {
+    pub name  : String,
+    pub color : [f32; 4],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LightKind {
+    Directional, // infinitely far away, shining along `direction`
+    Point,       // shining in every direction from `position`
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Light {
+    pub name      : String,
+    pub kind      : LightKind,
+    #[serde(default)]
+    pub position  : [f32; 3],
+    #[serde(default)]
+    pub direction : [f32; 3],
+    #[serde(default = "white")]
+    pub color     : [f32; 3],
+    #[serde(default = "one")]
+    pub intensity : f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CameraDescription {
+    pub name     : String,
+    pub position : [f32; 3],
+    #[serde(default)]
+    pub yaw      : f32, // degrees
+    #[serde(default)]
+    pub pitch    : f32, // degrees
+    #[serde(default = "default_fov_y")]
+    pub fov_y    : f32, // degrees
+    #[serde(default = "one")]
+    pub near     : f32,
+    #[serde(default = "default_far")]
+    pub far      : f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrackDescription {
+    pub property      : Property,
+    pub interpolation : Interpolation,
+    pub keys          : Vec<(f32, [f32; 3])>, // seconds and value. Rotations in degrees.
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClipDescription {
+    pub name      : String,
+    pub loop_mode : LoopMode,
+    pub tracks    : Vec<TrackDescription>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ControllerDescription {
+    Player,       // flown by the player
+    Path(String), // flies along the spline path in this file
+    Circuit,      // flies `toolbox::simple_heading_animation` around where the node is placed
+}
+
+// Changes to one part of a prefab instance. Whatever is left out stays as in the prefab.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PartOverride {
+    pub path     : String,             // of the part below the instance, e.g. "Body/Door"
+    #[serde(default)]
+    pub position : Option<[f32; 3]>,
+    #[serde(default)]
+    pub rotation : Option<[f32; 3]>,   // degrees
+    #[serde(default)]
+    pub scale    : Option<[f32; 3]>,
+    #[serde(default)]
+    pub mesh     : Option<String>,
+    #[serde(default)]
+    pub material : Option<String>,
+    #[serde(default)]
+    pub tags     : Vec<String>,        // added to those the part has
+}
+
+impl PartOverride {
+    fn apply(&self, part: &mut NodeDescription) {
+        if let Some(position) = self.position { part.position = position; }
+        if let Some(rotation) = self.rotation { part.rotation = rotation; }
+        if let Some(scale)    = self.scale    { part.scale    = scale; }
+        if self.mesh.is_some()     { part.mesh     = self.mesh.clone(); }
+        if self.material.is_some() { part.material = self.material.clone(); }
+        add_tags(&mut part.tags, &self.tags);
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeDescription {
+    pub name            : String,
+    #[serde(default)]
+    pub tags            : Vec<String>,
+    #[serde(default)]
+    pub position        : [f32; 3],
+    #[serde(default)]
+    pub rotation        : [f32; 3],                // degrees around the X, the Y and the Z axes
+    #[serde(default = "unit_scale")]
+    pub scale           : [f32; 3],
+    #[serde(default)]
+    pub reference_point : [f32; 3],
+    #[serde(default)]
+    pub mesh            : Option<String>,
+    #[serde(default)]
+    pub material        : Option<String>,
+    #[serde(default)]
+    pub controller      : Option<ControllerDescription>,
+    #[serde(default)]
+    pub animations      : Vec<(String, String)>,   // path of a part below this node, and the clip animating it
+    #[serde(default)]
+    pub children        : Vec<NodeDescription>,
+    #[serde(default)]
+    pub prefab          : Option<String>,          // make this node a copy of the prefab with this name
+    #[serde(default)]
+    pub overrides       : Vec<PartOverride>,       // changes to the parts of the prefab
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SceneFile {
+    #[serde(default)]
+    pub meshes     : Vec<MeshDescription>,
+    #[serde(default)]
+    pub materials  : Vec<Material>,
+    #[serde(default)]
+    pub lights     : Vec<Light>,
+    #[serde(default)]
+    pub cameras    : Vec<CameraDescription>,
+    #[serde(default)]
+    pub animations : Vec<ClipDescription>,
+    #[serde(default)]
+    pub prefabs    : Vec<NodeDescription>,  // subtrees to copy, named by their root
+    pub root       : NodeDescription,
+}
+
+fn one()           -> f32      { 1.0 }
+fn white()         -> [f32; 3] { [1.0, 1.0, 1.0] }
+fn unit_scale()    -> [f32; 3] { [1.0, 1.0, 1.0] }
+fn default_fov_y() -> f32      { 60.0 }
+fn default_far()   -> f32      { 1000.0 }
+
+fn vec3(v: &[f32; 3]) -> glm::Vec3 {
+    glm::vec3(v[0], v[1], v[2])
+}
+
+fn array(v: &glm::Vec3) -> [f32; 3] {
+    [v.x, v.y, v.z]
+}
+
+fn degrees(v: &[f32; 3]) -> glm::Vec3 {
+    glm::vec3(v[0].to_radians(), v[1].to_radians(), v[2].to_radians())
+}
+
+fn to_degrees(v: &glm::Vec3) -> [f32; 3] {
+    [v.x.to_degrees(), v.y.to_degrees(), v.z.to_degrees()]
+}
+
+fn add_tags(tags: &mut Vec<String>, more: &[String]) {
+    for tag in more {
+        if !tags.contains(tag) {
+            tags.push(tag.clone());
+        }
+    }
+}
+
+// The description below `description` at `path`, in the form `SceneNode::find` takes
+fn find_description<'a>(description: &'a NodeDescription, path: &str) -> Option<&'a NodeDescription> {
+    let mut description = description;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        description = description.children.iter().find(|child| child.name == name)?;
+    }
+    Some(description)
+}
+
+fn find_description_mut<'a>(description: &'a mut NodeDescription, path: &str) -> Option<&'a mut NodeDescription> {
+    let mut description = description;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        description = description.children.iter_mut().find(|child| child.name == name)?;
+    }
+    Some(description)
+}
+
+impl SceneFile {
+    pub fn load(path: &str) -> Result<SceneFile, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read scene {}: {}", path, e))?;
+        let scene: SceneFile = ron::from_str(&text).map_err(|e| format!("Failed to parse scene {}: {}", path, e))?;
+        scene.check_prefabs()?;
+        scene.check_patch_sizes()?;
+        Ok(scene)
+    }
+
+    // The tessellation shaders only take triangles, see `terrain::PATCH_SIZE`
+    fn check_patch_sizes(&self) -> Result<(), String> {
+        for mesh in &self.meshes {
+            match mesh.patch_size {
+                Some(patch_size) if patch_size != terrain::PATCH_SIZE => return Err(format!(
+                    "Mesh {} has patch size {}, but the terrain shaders tessellate patches of {}",
+                    mesh.name, patch_size, terrain::PATCH_SIZE,
+                )),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Make sure every prefab used exists, and that none of them contains itself however far down
+    fn check_prefabs(&self) -> Result<(), String> {
+        fn check<'a>(scene: &'a SceneFile, description: &'a NodeDescription, using: &mut Vec<&'a str>) -> Result<(), String> {
+            if let Some(name) = &description.prefab {
+                if using.contains(&name.as_str()) {
+                    return Err(format!("Prefab {} contains itself", name));
+                }
+                let prefab = scene.prefab(name).ok_or(format!("Unknown prefab {} on node {}", name, description.name))?;
+                using.push(name);
+                check(scene, prefab, using)?;
+                using.pop();
+            }
+            description.children.iter().try_for_each(|child| check(scene, child, using))
+        }
+        let mut using = vec![];
+        check(self, &self.root, &mut using)?;
+        self.prefabs.iter().try_for_each(|prefab| check(self, prefab, &mut using))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default().extensions(ron::extensions::Extensions::IMPLICIT_SOME))
+            .map_err(|e| format!("Failed to serialize scene: {}", e))?;
+        std::fs::write(path, text).map_err(|e| format!("Failed to write scene {}: {}", path, e))?;
+        println!("Saved scene to {}", path);
+        Ok(())
+    }
+
+    // Load every mesh, reading each file only once
+    pub fn load_meshes(&self) -> Result<HashMap<String, Mesh>, String> {
+        let mut files: HashMap<&str, Vec<(String, Mesh)>> = HashMap::new();
+        let mut meshes = HashMap::new();
+        for description in &self.meshes {
+            if !files.contains_key(description.file.as_str()) {
+                println!("Loading {}...", description.file);
+                files.insert(&description.file, Mesh::load_objects(&description.file)?);
+            }
+            let objects = &files[description.file.as_str()];
+            let mesh = match &description.object {
+                Some(object) => objects.iter().find(|(name, _)| name == object)
+                    .ok_or(format!("{} has no object called {}", description.file, object))?,
+                None if objects.len() == 1 => &objects[0],
+                None => return Err(format!("{} holds {} objects, say which one mesh {} is", description.file, objects.len(), description.name)),
+            };
+            meshes.insert(description.name.clone(), mesh.1.clone());
+        }
+        Ok(meshes)
+    }
+
+    pub fn prefab(&self, name: &str) -> Option<&NodeDescription> {
+        self.prefabs.iter().find(|prefab| prefab.name == name)
+    }
+
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.materials.iter().find(|material| material.name == name)
+    }
+
+    pub fn clip(&self, name: &str) -> Option<Clip> {
+        let description = self.animations.iter().find(|clip| clip.name == name)?;
+        let mut clip = Clip::new(&description.name, description.loop_mode);
+        for track in &description.tracks {
+            let mut built = Track::new(track.property, track.interpolation);
+            for (time, value) in &track.keys {
+                let value = if track.property == Property::Rotation { degrees(value) } else { vec3(value) };
+                built = built.with_key(*time, value);
+            }
+            clip = clip.with_track(built);
+        }
+        Some(clip)
+    }
+
+    // Build the node tree. `create_vao` is handed a mesh name, and returns the VAO and index count
+    // to draw it with. Nodes are colored by their material.
+    pub fn instantiate<F>(&self, create_vao: &mut F) -> Result<Node, String>
+        where F: FnMut(&str) -> Result<(u32, i32), String>
+    {
+        let mut templates = HashMap::new();
+        self.instantiate_node(&self.root, &mut templates, create_vao)
+    }
+
+    fn instantiate_node<F>(
+        &self,
+        description: &NodeDescription,
+        templates: &mut HashMap<String, Node>, // each prefab built once, to be copied
+        create_vao: &mut F,
+    ) -> Result<Node, String>
+        where F: FnMut(&str) -> Result<(u32, i32), String>
+    {
+        let mut node = match &description.prefab {
+            Some(name) => {
+                if !templates.contains_key(name) {
+                    let prefab = self.prefab(name).ok_or(format!("Unknown prefab {} on node {}", name, description.name))?;
+                    let template = self.instantiate_node(prefab, templates, create_vao)?;
+                    templates.insert(name.clone(), template);
+                }
+                templates[name].instantiate()
+            }
+            None => {
+                let mut node = SceneNode::new();
+                self.set_up_node(&mut node, description, create_vao)?;
+                node
+            }
+        };
+
+        for child in &description.children {
+            let child_node = self.instantiate_node(child, templates, create_vao)?;
+            node.add_child(&child_node);
+        }
+
+        // place a prefab's copy, and change the parts it overrides
+        if description.prefab.is_some() {
+            let resolved = self.resolve(description)?;
+            self.set_up_node(&mut node, &resolved, create_vao)?;
+            for part_override in &description.overrides {
+                let missing = || format!("{} has no part {} to override", description.name, part_override.path);
+                let part = node.find_mut(&part_override.path).ok_or_else(missing)?;
+                let part_description = find_description(&resolved, &part_override.path).ok_or_else(missing)?;
+                self.set_up_node(part, part_description, create_vao)?;
+            }
+        }
+        Ok(node)
+    }
+
+    // Give `node` the name, tags, placement, mesh and material of `description`, but not its children
+    fn set_up_node<F>(&self, node: &mut SceneNode, description: &NodeDescription, create_vao: &mut F) -> Result<(), String>
+        where F: FnMut(&str) -> Result<(u32, i32), String>
+    {
+        let (vao_id, index_count) = match &description.mesh {
+            Some(mesh) => create_vao(mesh)?,
+            None => (0, -1),
+        };
+        let color = match &description.material {
+            Some(name) => self.material(name).ok_or(format!("Unknown material {} on node {}", name, description.name))?.color,
+            None => [1.0, 1.0, 1.0, 1.0],
+        };
+        node.vao_id      = vao_id;
+        node.index_count = index_count;
+        node.color       = glm::vec4(color[0], color[1], color[2], color[3]);
+        node.set_name(&description.name);
+        for tag in &description.tags {
+            node.add_tag(tag);
+        }
+        node.position        = vec3(&description.position);
+        node.rotation        = degrees(&description.rotation);
+        node.scale           = vec3(&description.scale);
+        node.reference_point = vec3(&description.reference_point);
+        Ok(())
+    }
+
+    // `description` with every prefab instance in it written out in full. An instance is placed
+    // where it says, keeps the prefab's reference point, adds its tags, animations and children
+    // to the prefab's, and replaces the prefab's mesh, material and controller where it has one.
+    pub fn resolve(&self, description: &NodeDescription) -> Result<NodeDescription, String> {
+        let mut resolved = match &description.prefab {
+            Some(name) => {
+                let prefab = self.prefab(name).ok_or(format!("Unknown prefab {} on node {}", name, description.name))?;
+                let mut resolved = self.resolve(prefab)?;
+                resolved.name     = description.name.clone();
+                resolved.position = description.position;
+                resolved.rotation = description.rotation;
+                resolved.scale    = description.scale;
+                add_tags(&mut resolved.tags, &description.tags);
+                if description.mesh.is_some()       { resolved.mesh       = description.mesh.clone(); }
+                if description.material.is_some()   { resolved.material   = description.material.clone(); }
+                if description.controller.is_some() { resolved.controller = description.controller.clone(); }
+                resolved.animations.extend(description.animations.iter().cloned());
+                for part_override in &description.overrides {
+                    let part = find_description_mut(&mut resolved, &part_override.path)
+                        .ok_or(format!("{} has no part {} to override", description.name, part_override.path))?;
+                    part_override.apply(part);
+                }
+                resolved
+            }
+            None => NodeDescription { children: vec![], ..description.clone() },
+        };
+        for child in &description.children {
+            resolved.children.push(self.resolve(child)?);
+        }
+        Ok(resolved)
+    }
+
+    // Every node description with its path from the root, in the form `SceneNode::find` takes,
+    // with prefab instances written out in full
+    pub fn nodes_with_paths(&self) -> Result<Vec<(String, NodeDescription)>, String> {
+        fn collect(description: NodeDescription, path: String, out: &mut Vec<(String, NodeDescription)>) {
+            let mut description = description;
+            for child in std::mem::take(&mut description.children) {
+                let child_path = if path.is_empty() { child.name.clone() } else { format!("{}/{}", path, child.name) };
+                collect(child, child_path, out);
+            }
+            out.push((path, description));
+        }
+        let mut out = vec![];
+        collect(self.resolve(&self.root)?, String::new(), &mut out);
+        Ok(out)
+    }
+
+    // Bring the node tree up to date with `root`, e.g. to save where everything is now. Nodes are
+    // matched up by name, keeping what the scene graph doesn't know about (controllers, animations)
+    // for those that were described before. Prefab instances are saved as the prefab placed where
+    // the instance is now, with the overrides they had. `vao_names` gives the mesh behind each VAO,
+    // and nodes keep the material they were described with.
+    pub fn update_from(&mut self, root: &SceneNode, vao_names: &HashMap<u32, String>) {
+        self.root = describe_node(root, Some(&self.root), vao_names);
+    }
+}
+
+fn describe_node(
+    node: &SceneNode,
+    previous: Option<&NodeDescription>,
+    vao_names: &HashMap<u32, String>,
+) -> NodeDescription {
+    if let Some(previous) = previous.filter(|previous| previous.prefab.is_some()) {
+        return NodeDescription {
+            position : array(&node.position),
+            rotation : to_degrees(&node.rotation),
+            scale    : array(&node.scale),
+            ..previous.clone()
+        };
+    }
+    let (mesh, material) = match vao_names.get(&node.vao_id) {
+        Some(mesh) if node.index_count > 0 => (Some(mesh.clone()), previous.and_then(|previous| previous.material.clone())),
+        _ => (None, None),
+    };
+    NodeDescription {
+        name            : node.name.clone(),
+        tags            : node.tags.clone(),
+        position        : array(&node.position),
+        rotation        : to_degrees(&node.rotation),
+        scale           : array(&node.scale),
+        reference_point : array(&node.reference_point),
+        mesh,
+        material,
+        controller      : previous.and_then(|previous| previous.controller.clone()),
+        animations      : previous.map(|previous| previous.animations.clone()).unwrap_or_default(),
+        children        : node.children().map(|child| {
+            let previous_child = previous.and_then(|previous| previous.children.iter().find(|c| c.name == child.name));
+            describe_node(child, previous_child, vao_names)
+        }).collect(),
+        prefab          : None,
+        overrides       : vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"#![enable(implicit_some)]
+(
+    meshes: [
+        (name: "box",   file: "box.obj"),
+        (name: "rotor", file: "rotor.obj"),
+    ],
+    materials: [
+        (name: "grey", color: (0.5, 0.5, 0.5, 1.0)),
+        (name: "red",  color: (1.0, 0.0, 0.0, 1.0)),
+    ],
+    animations: [
+        (name: "spin", loop_mode: Loop, tracks: [
+            (property: Rotation, interpolation: Linear, keys: [(0.0, (0.0, 0.0, 0.0)), (1.0, (0.0, 180.0, 0.0))]),
+        ]),
+    ],
+    prefabs: [
+        (
+            name: "helicopter",
+            tags: ["helicopter"],
+            animations: [("Rotor", "spin")],
+            children: [
+                (name: "Body", mesh: "box", material: "grey", reference_point: (0.0, 1.0, 0.0)),
+                (name: "Rotor", mesh: "rotor", position: (0.0, 2.0, 0.0)),
+            ],
+        ),
+    ],
+    root: (
+        name: "Root",
+        children: [
+            (name: "Ground", mesh: "box", material: "grey", scale: (10.0, 1.0, 10.0)),
+            (
+                name: "Heli",
+                prefab: "helicopter",
+                position: (3.0, 0.0, 0.0),
+                controller: Circuit,
+                overrides: [(path: "Body", material: "red")],
+            ),
+            (
+                name: "Tower",
+                tags: ["landmark"],
+                mesh: "box",
+                controller: Path("tower.ron"),
+                animations: [("Light", "spin")],
+                children: [(name: "Light", mesh: "rotor", material: "red")],
+            ),
+        ],
+    ),
+)"#;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("gloom-rs-scene-{}-{}.ron", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    // Load a scene from `text`, as if from a file
+    fn parse(name: &str, text: &str) -> Result<SceneFile, String> {
+        let path = temp_path(name);
+        std::fs::write(&path, text).unwrap();
+        let scene = SceneFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        scene
+    }
+
+    // Hands out one VAO per mesh, as `main` does, and remembers which
+    #[derive(Default)]
+    struct Vaos {
+        ids   : HashMap<String, u32>,
+        names : HashMap<u32, String>,
+        calls : usize,
+    }
+
+    impl Vaos {
+        fn instantiate(&mut self, scene: &SceneFile) -> Result<Node, String> {
+            scene.instantiate(&mut |mesh: &str| {
+                self.calls += 1;
+                let next_id = self.ids.len() as u32 + 1;
+                let id = *self.ids.entry(mesh.to_string()).or_insert(next_id);
+                self.names.insert(id, mesh.to_string());
+                Ok((id, 36))
+            })
+        }
+    }
+
+    fn assert_near(a: [f32; 3], b: [f32; 3]) {
+        assert!(a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-4), "{:?} is not {:?}", a, b);
+    }
+
+    fn child<'a>(description: &'a NodeDescription, name: &str) -> &'a NodeDescription {
+        description.children.iter().find(|child| child.name == name).unwrap()
+    }
+
+    #[test]
+    fn saved_scenes_load_back_the_same() {
+        let mut scene = parse("round-trip", SCENE).unwrap();
+        let mut vaos = Vaos::default();
+        let mut root = vaos.instantiate(&scene).unwrap();
+        root.find_mut("Ground").unwrap().position = glm::vec3(1.0, 2.0, 3.0);
+        root.find_mut("Ground").unwrap().rotation = glm::vec3(0.0, 90f32.to_radians(), 0.0);
+        root.find_mut("Heli").unwrap().position = glm::vec3(5.0, 0.0, -5.0);
+
+        scene.update_from(&root, &vaos.names);
+        let path = temp_path("saved");
+        scene.save(&path).unwrap();
+        let saved = SceneFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let saved = saved.unwrap();
+
+        // what the scene graph doesn't know about is kept
+        let ground = child(&saved.root, "Ground");
+        assert_eq!(ground.mesh.as_deref(), Some("box"));
+        assert_eq!(ground.material.as_deref(), Some("grey"));
+        assert_near(ground.position, [1.0, 2.0, 3.0]);
+        assert_near(ground.rotation, [0.0, 90.0, 0.0]);
+        assert_near(ground.scale, [10.0, 1.0, 10.0]);
+        let tower = child(&saved.root, "Tower");
+        assert_eq!(tower.tags, ["landmark"]);
+        assert_eq!(tower.controller, Some(ControllerDescription::Path("tower.ron".to_string())));
+        assert_eq!(tower.animations, [("Light".to_string(), "spin".to_string())]);
+        assert_eq!(child(tower, "Light").material.as_deref(), Some("red"));
+
+        // prefab instances stay instances, moved to where they are now
+        let heli = child(&saved.root, "Heli");
+        assert_eq!(heli.prefab.as_deref(), Some("helicopter"));
+        assert!(heli.children.is_empty());
+        assert_eq!(heli.controller, Some(ControllerDescription::Circuit));
+        assert_eq!(heli.overrides.len(), 1);
+        assert_eq!(heli.overrides[0].material.as_deref(), Some("red"));
+        assert_near(heli.position, [5.0, 0.0, -5.0]);
+        assert_eq!(saved.prefabs.len(), 1);
+        assert_eq!(saved.animations.len(), 1);
+
+        // and the saved scene builds the tree it was saved from
+        let mut saved_vaos = Vaos::default();
+        let saved_root = saved_vaos.instantiate(&saved).unwrap();
+        let before: Vec<_> = root.depth_first().collect();
+        let after: Vec<_> = saved_root.depth_first().collect();
+        assert_eq!(before.len(), after.len());
+        for (a, b) in before.iter().zip(&after) {
+            assert_eq!(a.name, b.name);
+            assert_eq!(a.tags, b.tags);
+            assert_eq!(vaos.names.get(&a.vao_id), saved_vaos.names.get(&b.vao_id), "{}", a.name);
+            assert_eq!(a.color, b.color, "{}", a.name);
+            assert_near(array(&a.position), array(&b.position));
+            assert_near(array(&a.rotation), array(&b.rotation));
+            assert_near(array(&a.scale), array(&b.scale));
+            assert_near(array(&a.reference_point), array(&b.reference_point));
+        }
+    }
+
+    const PREFABS: &str = r#"#![enable(implicit_some)]
+(
+    meshes: [
+        (name: "box",   file: "box.obj"),
+        (name: "rotor", file: "rotor.obj"),
+        (name: "gun",   file: "gun.obj"),
+    ],
+    materials: [
+        (name: "grey", color: (0.5, 0.5, 0.5, 1.0)),
+        (name: "red",  color: (1.0, 0.0, 0.0, 1.0)),
+    ],
+    prefabs: [
+        (
+            name: "helicopter",
+            tags: ["helicopter"],
+            children: [
+                (name: "Body", mesh: "box", material: "grey", children: [(name: "Door", mesh: "box")]),
+                (name: "Rotor", mesh: "rotor", position: (0.0, 2.0, 0.0)),
+            ],
+        ),
+        (
+            name: "pair",
+            children: [
+                (name: "Lead", prefab: "helicopter", overrides: [(path: "Body", material: "red")]),
+                (name: "Wing", prefab: "helicopter", position: (4.0, 0.0, 0.0)),
+            ],
+        ),
+    ],
+    root: (
+        name: "Root",
+        children: [
+            (
+                name: "Armed",
+                prefab: "helicopter",
+                position: (10.0, 0.0, 0.0),
+                tags: ["enemy"],
+                overrides: [
+                    (path: "Body/Door", mesh: "gun", material: "red", tags: ["weapon"]),
+                    (path: "Rotor", position: (0.0, 3.0, 0.0), scale: (2.0, 1.0, 2.0)),
+                ],
+            ),
+            (name: "Plain", prefab: "helicopter"),
+            (name: "Pair", prefab: "pair", position: (0.0, 0.0, 20.0), overrides: [(path: "Wing/Rotor", material: "red")]),
+        ],
+    ),
+)"#;
+
+    fn red() -> glm::Vec4 {
+        glm::vec4(1.0, 0.0, 0.0, 1.0)
+    }
+
+    #[test]
+    fn overrides_change_only_their_part() {
+        let scene = parse("overrides", PREFABS).unwrap();
+        let mut vaos = Vaos::default();
+        let root = vaos.instantiate(&scene).unwrap();
+
+        let armed = root.find("Armed").unwrap();
+        assert_eq!(armed.tags, ["helicopter", "enemy"]);
+        assert_eq!(armed.position, glm::vec3(10.0, 0.0, 0.0));
+        let door = armed.find("Body/Door").unwrap();
+        assert_eq!(vaos.names[&door.vao_id], "gun");
+        assert_eq!(door.color, red());
+        assert!(door.has_tag("weapon"));
+        let rotor = armed.find("Rotor").unwrap();
+        assert_eq!(rotor.position, glm::vec3(0.0, 3.0, 0.0));
+        assert_eq!(rotor.scale, glm::vec3(2.0, 1.0, 2.0));
+        assert_eq!(vaos.names[&rotor.vao_id], "rotor");
+
+        // the rest, and the other instances, are as in the prefab
+        assert_eq!(armed.find("Body").unwrap().color, glm::vec4(0.5, 0.5, 0.5, 1.0));
+        let plain = root.find("Plain").unwrap();
+        assert_eq!(vaos.names[&plain.find("Body/Door").unwrap().vao_id], "box");
+        assert_eq!(plain.find("Rotor").unwrap().position, glm::vec3(0.0, 2.0, 0.0));
+
+        // resolving writes the same out in full
+        let resolved = scene.resolve(&scene.root).unwrap();
+        let door = find_description(&resolved, "Armed/Body/Door").unwrap();
+        assert_eq!(door.mesh.as_deref(), Some("gun"));
+        assert_eq!(door.material.as_deref(), Some("red"));
+        assert_eq!(door.tags, ["weapon"]);
+        assert_eq!(find_description(&resolved, "Plain/Body/Door").unwrap().mesh.as_deref(), Some("box"));
+    }
+
+    #[test]
+    fn prefabs_nest() {
+        let scene = parse("nested", PREFABS).unwrap();
+        let mut vaos = Vaos::default();
+        let root = vaos.instantiate(&scene).unwrap();
+
+        let pair = root.find("Pair").unwrap();
+        assert_eq!(pair.position, glm::vec3(0.0, 0.0, 20.0));
+        assert_eq!(pair.find("Wing").unwrap().position, glm::vec3(4.0, 0.0, 0.0));
+        assert!(pair.find("Lead").unwrap().has_tag("helicopter"));
+        // overrides from inside the prefab and from the instance of it both apply
+        assert_eq!(pair.find("Lead/Body").unwrap().color, red());
+        assert_eq!(pair.find("Wing/Rotor").unwrap().color, red());
+        assert_eq!(pair.find("Wing/Body").unwrap().color, glm::vec4(0.5, 0.5, 0.5, 1.0));
+        assert_eq!(pair.find("Lead/Rotor").unwrap().color, glm::vec4(1.0, 1.0, 1.0, 1.0));
+
+        let paths: Vec<String> = scene.nodes_with_paths().unwrap().into_iter().map(|(path, _)| path).collect();
+        for path in ["Pair/Lead/Body/Door", "Pair/Wing/Rotor", "Armed/Body/Door"] {
+            assert!(paths.iter().any(|p| p == path), "{} not in {:?}", path, paths);
+        }
+    }
+
+    #[test]
+    fn instances_share_a_vao_per_mesh() {
+        let scene = parse("vaos", PREFABS).unwrap();
+        let mut vaos = Vaos::default();
+        let root = vaos.instantiate(&scene).unwrap();
+
+        let mut vao_of_mesh: HashMap<&str, u32> = HashMap::new();
+        for node in root.depth_first().filter(|node| node.index_count > 0) {
+            let mesh = vaos.names[&node.vao_id].as_str();
+            assert_eq!(*vao_of_mesh.entry(mesh).or_insert(node.vao_id), node.vao_id, "{}", node.name);
+        }
+        assert_eq!(vao_of_mesh.len(), 3);
+        // each prefab is built once and copied after that, so only the three parts of the
+        // helicopter and the four overridden parts ask for a VAO
+        assert_eq!(vaos.calls, 7);
+        let bodies: Vec<_> = root.depth_first().filter(|node| node.name == "Body").collect();
+        assert_eq!(bodies.len(), 4);
+        assert!(bodies.iter().all(|body| body.vao_id == bodies[0].vao_id));
+    }
+
+    #[test]
+    fn prefabs_containing_themselves_are_errors() {
+        let scene = r#"#![enable(implicit_some)]
+(
+    prefabs: [
+        (name: "a", children: [(name: "B", prefab: "b")]),
+        (name: "b", children: [(name: "A", prefab: "a")]),
+    ],
+    root: (name: "Root", children: [(name: "A", prefab: "a")]),
+)"#;
+        assert_eq!(parse("cycle", scene).err().as_deref(), Some("Prefab a contains itself"));
+
+        // even when nothing uses them
+        let scene = r#"#![enable(implicit_some)]
+(
+    prefabs: [(name: "a", children: [(name: "A", prefab: "a")])],
+    root: (name: "Root"),
+)"#;
+        assert_eq!(parse("unused-cycle", scene).err().as_deref(), Some("Prefab a contains itself"));
+
+        let scene = r#"#![enable(implicit_some)]
+(root: (name: "Root", children: [(name: "A", prefab: "missing")]))"#;
+        assert_eq!(parse("unknown", scene).err().as_deref(), Some("Unknown prefab missing on node A"));
+    }
+
+    #[test]
+    fn overrides_of_missing_parts_are_errors() {
+        let scene = parse("missing-part", &PREFABS.replace("path: \"Rotor\"", "path: \"Body/Rotor\"")).unwrap();
+        let expected = "Armed has no part Body/Rotor to override";
+        assert_eq!(scene.resolve(&scene.root).err().as_deref(), Some(expected));
+        assert_eq!(Vaos::default().instantiate(&scene).err().as_deref(), Some(expected));
+    }
+}
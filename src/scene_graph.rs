@@ -1,5 +1,6 @@
 extern crate nalgebra_glm as glm;
 
+use std::collections::VecDeque;
 use std::mem::ManuallyDrop;
 use std::pin::Pin;
 
@@ -17,6 +18,9 @@ use std::pin::Pin;
 pub type Node = ManuallyDrop<Pin<Box<SceneNode>>>;
 
 pub struct SceneNode {
+    pub name            : String,      // What I am called, unique among my siblings to be found by path
+    pub tags            : Vec<String>, // What kinds of thing I am, e.g. "helicopter"
+
     pub position        : glm::Vec3,   // Where I should be in relation to my parent
     pub rotation        : glm::Vec3,   // How I should be rotated, around the X, the Y and the Z axes
     pub scale           : glm::Vec3,   // How I should be scaled
@@ -24,6 +28,7 @@ pub struct SceneNode {
 
     pub vao_id      : u32,             // What I should draw
     pub index_count : i32,             // How much of it there is to draw
+    pub color       : glm::Vec4,       // What my colors are multiplied with
 
     pub children: Vec<*mut SceneNode>, // Those I command
 }
@@ -32,24 +37,30 @@ impl SceneNode {
 
     pub fn new() -> Node {
         ManuallyDrop::new(Pin::new(Box::new(SceneNode {
+            name            : String::new(),
+            tags            : vec![],
             position        : glm::zero(),
             rotation        : glm::zero(),
             scale           : glm::vec3(1.0, 1.0, 1.0),
             reference_point : glm::zero(),
             vao_id          : 0,
             index_count     : -1,
+            color           : glm::vec4(1.0, 1.0, 1.0, 1.0),
             children        : vec![],
         })))
     }
 
     pub fn from_vao(vao_id: u32, index_count: i32) -> Node {
         ManuallyDrop::new(Pin::new(Box::new(SceneNode {
+            name            : String::new(),
+            tags            : vec![],
             position        : glm::zero(),
             rotation        : glm::zero(),
             scale           : glm::vec3(1.0, 1.0, 1.0),
             reference_point : glm::zero(),
             vao_id,
             index_count,
+            color           : glm::vec4(1.0, 1.0, 1.0, 1.0),
             children: vec![],
         })))
     }
@@ -58,6 +69,23 @@ impl SceneNode {
         self.children.push(child as *const SceneNode as *mut SceneNode)
     }
 
+    // A copy of me and everything below me, drawing the same VAOs
+    pub fn instantiate(&self) -> Node {
+        let mut node = SceneNode::from_vao(self.vao_id, self.index_count);
+        node.name            = self.name.clone();
+        node.tags            = self.tags.clone();
+        node.position        = self.position;
+        node.rotation        = self.rotation;
+        node.scale           = self.scale;
+        node.reference_point = self.reference_point;
+        node.color           = self.color;
+        for child in self.children() {
+            let child_node = child.instantiate();
+            node.add_child(&child_node);
+        }
+        node
+    }
+
     #[allow(dead_code)]
     pub fn get_child(& mut self, index: usize) -> & mut SceneNode {
         unsafe {
@@ -65,6 +93,120 @@ impl SceneNode {
         }
     }
 
+    // My transformation relative to my parent: rotate and scale about the reference point, then move
+    pub fn local_transform(&self) -> glm::Mat4 {
+        let mut trans: glm::Mat4 = glm::identity();
+        // move to origin
+        trans = glm::translation(&-self.reference_point) * trans;
+        // scale and rotate
+        trans = glm::scaling(&self.scale) * trans;
+        trans = glm::rotation(self.rotation.z, &glm::vec3(0.0, 0.0, 1.0)) * trans;
+        trans = glm::rotation(self.rotation.y, &glm::vec3(0.0, 1.0, 0.0)) * trans;
+        trans = glm::rotation(self.rotation.x, &glm::vec3(1.0, 0.0, 0.0)) * trans;
+        // move back
+        trans = glm::translation(&self.reference_point) * trans;
+
+        glm::translation(&self.position) * trans
+    }
+
+    // The full transformation of `node` when drawing from me, found by searching my subtree
+    pub fn transform_of(&self, node: *const SceneNode) -> Option<glm::Mat4> {
+        let trans = self.local_transform();
+        if std::ptr::eq(self, node) {
+            return Some(trans);
+        }
+        for &child in &self.children {
+            if let Some(child_trans) = unsafe { (*child).transform_of(node) } {
+                return Some(trans * child_trans);
+            }
+        }
+        None
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn add_tag(&mut self, tag: &str) {
+        if !self.has_tag(tag) {
+            self.tags.push(tag.to_string());
+        }
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = &SceneNode> {
+        self.children.iter().map(|&child| unsafe { &*child })
+    }
+
+    // Find a descendant by the names along the way down to it, e.g. "Helicopter3/Body/Door"
+    pub fn find(&self, path: &str) -> Option<&SceneNode> {
+        let mut node = self;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = node.children().find(|child| child.name == name)?;
+        }
+        Some(node)
+    }
+
+    pub fn find_mut(&mut self, path: &str) -> Option<&mut SceneNode> {
+        let mut node: *mut SceneNode = self;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = unsafe { (*node).children.iter().copied().find(|&child| (*child).name == name)? };
+        }
+        unsafe { Some(&mut *node) }
+    }
+
+    // The first node called `name` in my subtree, including me, searching depth first
+    pub fn find_by_name(&self, name: &str) -> Option<&SceneNode> {
+        self.depth_first().find(|node| node.name == name)
+    }
+
+    pub fn find_by_name_mut(&mut self, name: &str) -> Option<&mut SceneNode> {
+        if self.name == name {
+            return Some(self);
+        }
+        self.children.iter().find_map(|&child| unsafe { (*child).find_by_name_mut(name) })
+    }
+
+    // Every node in my subtree, including me, tagged with `tag`
+    pub fn find_by_tag(&self, tag: &str) -> Vec<&SceneNode> {
+        self.depth_first().filter(|node| node.has_tag(tag)).collect()
+    }
+
+    // The names leading from me down to `node`, in the form `find` takes
+    pub fn path_to(&self, node: *const SceneNode) -> Option<String> {
+        if std::ptr::eq(self, node) {
+            return Some(String::new());
+        }
+        self.children().find_map(|child| {
+            child.path_to(node).map(|rest| if rest.is_empty() { child.name.clone() } else { format!("{}/{}", child.name, rest) })
+        })
+    }
+
+    // Me, then each of my children's subtrees in order
+    pub fn depth_first(&self) -> DepthFirst<'_> {
+        DepthFirst { stack: vec![self] }
+    }
+
+    // Me, then my children, then my grandchildren...
+    pub fn breadth_first(&self) -> BreadthFirst<'_> {
+        BreadthFirst { queue: VecDeque::from([self]) }
+    }
+
+    // Call `visitor` on every node in my subtree depth first, with how far below me it is
+    pub fn visit<F: FnMut(&mut SceneNode, usize)>(&mut self, visitor: &mut F) {
+        self.visit_at_depth(visitor, 0);
+    }
+
+    fn visit_at_depth<F: FnMut(&mut SceneNode, usize)>(&mut self, visitor: &mut F, depth: usize) {
+        visitor(self, depth);
+        for &child in &self.children {
+            unsafe { (*child).visit_at_depth(visitor, depth + 1) };
+        }
+    }
+
     #[allow(dead_code)]
     pub fn get_n_children(&self) -> usize {
         self.children.len()
@@ -74,6 +216,8 @@ impl SceneNode {
     pub fn print(&self) {
         println!(
 "SceneNode {{
+    Name:      {}
+    Tags:      {:?}
     VAO:       {}
     Indices:   {}
     Children:  {}
@@ -81,6 +225,8 @@ impl SceneNode {
     Rotation:  [{:.2}, {:.2}, {:.2}]
     Reference: [{:.2}, {:.2}, {:.2}]
 }}",
+            self.name,
+            self.tags,
             self.vao_id,
             self.index_count,
             self.children.len(),
@@ -99,6 +245,50 @@ impl SceneNode {
 }
 
 
+// Conversions between quaternions and the angles in `SceneNode::rotation`, which rotate
+// around the Z axis first, then around Y, and finally around X.
+pub fn euler_to_quat(rotation: &glm::Vec3) -> glm::Quat {
+    glm::quat_angle_axis(rotation.x, &glm::vec3(1.0, 0.0, 0.0))
+        * glm::quat_angle_axis(rotation.y, &glm::vec3(0.0, 1.0, 0.0))
+        * glm::quat_angle_axis(rotation.z, &glm::vec3(0.0, 0.0, 1.0))
+}
+
+pub fn quat_to_euler(rotation: &glm::Quat) -> glm::Vec3 {
+    let m = glm::quat_to_mat3(rotation);
+    glm::vec3(
+        (-m[(1, 2)]).atan2(m[(2, 2)]),
+        m[(0, 2)].clamp(-1.0, 1.0).asin(),
+        (-m[(0, 1)]).atan2(m[(0, 0)]),
+    )
+}
+
+pub struct DepthFirst<'a> {
+    stack: Vec<&'a SceneNode>,
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = &'a SceneNode;
+    fn next(&mut self) -> Option<&'a SceneNode> {
+        let node = self.stack.pop()?;
+        // pushed in reverse, so the first child comes out first
+        self.stack.extend(node.children.iter().rev().map(|&child| unsafe { &*child }));
+        Some(node)
+    }
+}
+
+pub struct BreadthFirst<'a> {
+    queue: VecDeque<&'a SceneNode>,
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = &'a SceneNode;
+    fn next(&mut self) -> Option<&'a SceneNode> {
+        let node = self.queue.pop_front()?;
+        self.queue.extend(node.children.iter().map(|&child| unsafe { &*child }));
+        Some(node)
+    }
+}
+
 // You can also use square brackets to access the children of a SceneNode
 use std::ops::{Index, IndexMut};
 impl Index<usize> for SceneNode {
@@ -116,3 +306,90 @@ impl IndexMut<usize> for SceneNode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str) -> Node {
+        let mut node = SceneNode::new();
+        node.set_name(name);
+        node
+    }
+
+    // Root
+    // ├── A
+    // │   ├── B
+    // │   │   └── C
+    // │   └── D
+    // └── E
+    //     └── B
+    fn tree() -> Node {
+        let mut root = named("Root");
+        let mut a = named("A");
+        let mut b = named("B");
+        let c = named("C");
+        let d = named("D");
+        let mut e = named("E");
+        let other_b = named("B");
+        b.add_child(&c);
+        a.add_child(&b);
+        a.add_child(&d);
+        e.add_child(&other_b);
+        root.add_child(&a);
+        root.add_child(&e);
+        root
+    }
+
+    fn names<'a>(nodes: impl Iterator<Item = &'a SceneNode>) -> Vec<&'a str> {
+        nodes.map(|node| node.name.as_str()).collect()
+    }
+
+    #[test]
+    fn paths_find_nodes_by_the_names_down_to_them() {
+        let mut root = tree();
+        assert_eq!(root.find("A/B/C").unwrap().name, "C");
+        assert_eq!(root.find("E/B").unwrap().children.len(), 0);
+        assert_eq!(root.find("A/B").unwrap().children.len(), 1);
+        assert_eq!(root.find("").unwrap().name, "Root");
+        assert!(root.find("A/C").is_none());
+        assert!(root.find("A/B/C/D").is_none());
+        assert!(root.find("Missing").is_none());
+
+        root.find_mut("A/B/C").unwrap().position = glm::vec3(1.0, 2.0, 3.0);
+        assert_eq!(root[0][0][0].position, glm::vec3(1.0, 2.0, 3.0));
+        assert!(root.find_mut("E/C").is_none());
+    }
+
+    #[test]
+    fn names_find_the_first_node_depth_first() {
+        let mut root = tree();
+        assert_eq!(root.find_by_name("B").unwrap().children.len(), 1);
+        assert!(root.find_by_name("Missing").is_none());
+        root.find_by_name_mut("D").unwrap().add_tag("found");
+        assert!(root.find("A/D").unwrap().has_tag("found"));
+        assert!(root.find_by_name_mut("Missing").is_none());
+    }
+
+    #[test]
+    fn iterators_visit_in_order() {
+        let root = tree();
+        assert_eq!(names(root.depth_first()), ["Root", "A", "B", "C", "D", "E", "B"]);
+        assert_eq!(names(root.breadth_first()), ["Root", "A", "E", "B", "D", "B", "C"]);
+        assert_eq!(names(root.find("A/B/C").unwrap().depth_first()), ["C"]);
+    }
+
+    #[test]
+    fn paths_to_nodes_lead_back_to_them() {
+        let root = tree();
+        for node in root.depth_first() {
+            let path = root.path_to(node).unwrap();
+            assert!(std::ptr::eq(root.find(&path).unwrap(), node), "{}", path);
+        }
+        assert_eq!(root.path_to(root.find("E/B").unwrap()).unwrap(), "E/B");
+        assert_eq!(root.path_to(&**root).unwrap(), "");
+
+        let elsewhere = named("A");
+        assert!(root.path_to(&**elsewhere).is_none());
+    }
+}
@@ -1,39 +1,343 @@
+extern crate nalgebra_glm as glm;
+
+use crate::preprocessor::{self, Preprocessed};
+use crate::program_cache;
 use gl;
+use gl::types::GLenum;
 use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
     ptr,
     str,
     ffi::CString,
-    path::Path,
+    path::{Path, PathBuf},
+    time::SystemTime,
 };
 
 pub struct Shader {
     pub program_id: u32,
+    files: Vec::<AttachedFile>,                 // to build it again from
+    watched: Vec::<(String, Option<SystemTime>)>, // files its sources were read from, and when they were last modified
+    pub uniforms: HashMap<String, Variable>,    // the active uniforms outside of blocks, by name
+    pub attributes: HashMap<String, Variable>,  // the active vertex attributes, by name
+    warned: RefCell<HashSet<String>>,           // uniforms already warned about, to warn only once
+    binary_cache: Option<PathBuf>,              // to build it again with
+    pub work_group_size: Option<[u32; 3]>,      // the `local_size` of a compute program
+}
+
+// An active uniform or attribute of a linked program
+#[derive(Clone, Copy, Debug)]
+pub struct Variable {
+    pub location: i32,
+    pub kind: GLenum, // e.g. gl::FLOAT_MAT4
+    pub size: i32,    // elements, for arrays
+}
+
+// What can be given to `Shader::set_uniform`, and the GLSL types it may be given to
+pub trait UniformValue {
+    const NAME: &'static str;
+    const KINDS: &'static [GLenum];
+    unsafe fn set(&self, program_id: u32, location: i32);
+}
+
+// A texture unit, to set a sampler uniform to
+#[derive(Clone, Copy, Debug)]
+pub struct Sampler(pub i32);
+
+impl UniformValue for glm::Mat4 {
+    const NAME: &'static str = "mat4";
+    const KINDS: &'static [GLenum] = &[gl::FLOAT_MAT4];
+    unsafe fn set(&self, program_id: u32, location: i32) {
+        gl::ProgramUniformMatrix4fv(program_id, location, 1, gl::FALSE, self.as_ptr());
+    }
+}
+
+impl UniformValue for glm::Vec3 {
+    const NAME: &'static str = "vec3";
+    const KINDS: &'static [GLenum] = &[gl::FLOAT_VEC3];
+    unsafe fn set(&self, program_id: u32, location: i32) {
+        gl::ProgramUniform3fv(program_id, location, 1, self.as_ptr());
+    }
+}
+
+impl UniformValue for f32 {
+    const NAME: &'static str = "float";
+    const KINDS: &'static [GLenum] = &[gl::FLOAT];
+    unsafe fn set(&self, program_id: u32, location: i32) {
+        gl::ProgramUniform1f(program_id, location, *self);
+    }
+}
+
+impl UniformValue for i32 {
+    const NAME: &'static str = "int";
+    const KINDS: &'static [GLenum] = &[gl::INT, gl::BOOL];
+    unsafe fn set(&self, program_id: u32, location: i32) {
+        gl::ProgramUniform1i(program_id, location, *self);
+    }
+}
+
+impl UniformValue for Sampler {
+    const NAME: &'static str = "sampler";
+    const KINDS: &'static [GLenum] = &[
+        gl::SAMPLER_1D, gl::SAMPLER_2D, gl::SAMPLER_3D, gl::SAMPLER_CUBE,
+        gl::SAMPLER_2D_ARRAY, gl::SAMPLER_2D_SHADOW, gl::SAMPLER_2D_MULTISAMPLE, gl::SAMPLER_BUFFER,
+        gl::INT_SAMPLER_2D, gl::UNSIGNED_INT_SAMPLER_2D,
+    ];
+    unsafe fn set(&self, program_id: u32, location: i32) {
+        gl::ProgramUniform1i(program_id, location, self.0);
+    }
+}
+
+// The GLSL name of a uniform or attribute type, for warnings
+fn kind_name(kind: GLenum) -> String {
+    match kind {
+        gl::FLOAT      => "float".to_string(),
+        gl::FLOAT_VEC2 => "vec2".to_string(),
+        gl::FLOAT_VEC3 => "vec3".to_string(),
+        gl::FLOAT_VEC4 => "vec4".to_string(),
+        gl::FLOAT_MAT3 => "mat3".to_string(),
+        gl::FLOAT_MAT4 => "mat4".to_string(),
+        gl::INT        => "int".to_string(),
+        gl::BOOL       => "bool".to_string(),
+        gl::SAMPLER_2D => "sampler2D".to_string(),
+        kind           => format!("type 0x{:x}", kind),
+    }
+}
+
+// The active uniforms or attributes of a linked program, read with the matching `ACTIVE_*`
+// counts and `GetActive*`. Array elements are found under the array's own name too.
+unsafe fn reflect(
+    program_id: u32,
+    count: GLenum,
+    max_length: GLenum,
+    get_active: unsafe fn(u32, u32, i32, *mut i32, *mut i32, *mut GLenum, *mut gl::types::GLchar),
+    get_location: unsafe fn(u32, *const gl::types::GLchar) -> i32,
+) -> HashMap<String, Variable> {
+    let (mut active, mut longest) = (0, 0);
+    gl::GetProgramiv(program_id, count, &mut active);
+    gl::GetProgramiv(program_id, max_length, &mut longest);
+    let mut variables = HashMap::new();
+    for index in 0..active.max(0) as u32 {
+        let mut name = vec![0u8; longest.max(1) as usize];
+        let (mut written, mut size, mut kind) = (0, 0, 0);
+        get_active(program_id, index, name.len() as i32, &mut written, &mut size, &mut kind, name.as_mut_ptr() as *mut gl::types::GLchar);
+        name.truncate(written.max(0) as usize);
+        let name = String::from_utf8_lossy(&name).into_owned();
+        let location = match CString::new(name.as_str()) {
+            Ok(c_name) => get_location(program_id, c_name.as_ptr()),
+            Err(_) => -1,
+        };
+        // built in, or in a block
+        if location < 0 {
+            continue;
+        }
+        let variable = Variable { location, kind, size };
+        if let Some(array) = name.strip_suffix("[0]") {
+            variables.insert(array.to_string(), variable);
+        }
+        variables.insert(name, variable);
+    }
+    variables
 }
 
 pub struct ShaderBuilder {
     program_id: u32,
     shaders: Vec::<u32>,
+    files: Vec::<AttachedFile>,
+    defines: Vec::<(String, String)>, // for the files attached from now on
+    watched: Vec::<(String, Option<SystemTime>)>,
+    binary_cache: Option<PathBuf>,                // see `with_binary_cache`
+    include_directory: PathBuf,                   // see `with_include_directory`
+    stages: Vec::<ShaderType>,                    // attached so far
+    // with a cache, the stages left to compile when linking
+    pending: Vec::<(Preprocessed, ShaderType, Option<String>)>,
+}
+
+// A source file, and the defines it was attached with
+type AttachedFile = (String, Vec::<(String, String)>);
+
+// When a file was last modified, if we can tell
+pub(crate) fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ShaderType {
     Vertex,
     Fragment,
     TessellationControl,
     TessellationEvaluation,
     Geometry,
+    Compute,
+}
+
+// Why a shader program could not be built. Nothing the builder created is left behind.
+#[derive(Debug)]
+pub enum ShaderError {
+    // The source file could not be read
+    Read { path: String, error: String },
+    // The file's extension doesn't say which stage it is, see `ShaderType::from_ext`
+    UnknownExtension { path: String },
+    // An `#include` could not be followed, see `src/preprocessor.rs`
+    Include { path: String, line: u32, message: String },
+    // The driver rejected the source. `lines` are the files and lines the log complains about,
+    // as written before preprocessing.
+    Compile { path: Option<String>, stage: ShaderType, log: String, lines: Vec<(String, u32)> },
+    // The stages compiled, but don't fit together
+    Link { paths: Vec<String>, log: String },
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderError::Read { path, error } => write!(f, "Failed to read shader source {}: {}", path, error),
+            ShaderError::Include { path, line, message } => write!(f, "{}:{}: {}", path, line, message),
+            ShaderError::UnknownExtension { path } => write!(f, "Unknown shader stage for {}, expected one of .vert, .frag, .tcs, .tes, .geom or .comp", path),
+            ShaderError::Compile { path, stage, log, lines } => {
+                write!(f, "{:?} shader {} failed to compile", stage, path.as_deref().unwrap_or("source"))?;
+                if !lines.is_empty() {
+                    let lines: Vec<String> = lines.iter().map(|(file, line)| format!("{}:{}", file, line)).collect();
+                    write!(f, " at {}", lines.join(", "))?;
+                }
+                write!(f, ":\n{}", log.trim_end())
+            }
+            ShaderError::Link { paths, log } => write!(f, "Shader program {} failed to link:\n{}", paths.join(" + "), log.trim_end()),
+        }
+    }
+}
+
+// The line numbers an info log points at. Drivers write them differently: "0(12) : error"
+// (NVIDIA), "0:12(5): error" (Mesa) and "ERROR: 0:12: " (AMD, Intel), where the 0 is the
+// number of the source string.
+fn parse_line_numbers(log: &str) -> Vec<u32> {
+    let mut lines = vec![];
+    for message in log.lines() {
+        let message = message.trim_start_matches("ERROR: ").trim_start_matches("WARNING: ");
+        let string_end = message.find(|c: char| !c.is_ascii_digit()).unwrap_or(message.len());
+        if string_end == 0 {
+            continue;
+        }
+        let number: String = match message[string_end..].chars().next() {
+            Some('(') | Some(':') => message[string_end + 1..].chars().take_while(|c| c.is_ascii_digit()).collect(),
+            _ => continue,
+        };
+        if let Ok(line) = number.parse() {
+            if !lines.contains(&line) {
+                lines.push(line);
+            }
+        }
+    }
+    lines
+}
+
+// The info log of a shader or program, read with the matching `Get*iv` and `Get*InfoLog`
+unsafe fn info_log(
+    id: u32,
+    get_iv: unsafe fn(u32, gl::types::GLenum, *mut i32),
+    get_log: unsafe fn(u32, i32, *mut i32, *mut gl::types::GLchar),
+) -> String {
+    let mut length = 0;
+    get_iv(id, gl::INFO_LOG_LENGTH, &mut length);
+    let mut log = vec![0u8; length.max(1) as usize];
+    let mut written = 0;
+    get_log(id, log.len() as i32, &mut written, log.as_mut_ptr() as *mut gl::types::GLchar);
+    log.truncate(written.max(0) as usize);
+    String::from_utf8_lossy(&log).into_owned()
 }
 
 impl Shader {
-    // Make sure the shader is active before calling this
-    pub unsafe fn get_uniform_location(&self, name: &str) -> i32 {
-        let name_cstr = CString::new(name).expect("CString::new failed");
-        gl::GetUniformLocation(self.program_id, name_cstr.as_ptr())
+    // -1 if the program has no such uniform, or the compiler found it unused
+    pub fn get_uniform_location(&self, name: &str) -> i32 {
+        self.uniforms.get(name).map_or(-1, |uniform| uniform.location)
+    }
+
+    pub fn get_attribute_location(&self, name: &str) -> i32 {
+        self.attributes.get(name).map_or(-1, |attribute| attribute.location)
+    }
+
+    // Set a uniform of this program, whether or not it is active. A uniform the program doesn't
+    // have, or of another type, is warned about once and left alone.
+    pub unsafe fn set_uniform<T: UniformValue>(&self, name: &str, value: T) {
+        let problem = match self.uniforms.get(name) {
+            Some(uniform) if T::KINDS.contains(&uniform.kind) => {
+                value.set(self.program_id, uniform.location);
+                return;
+            }
+            Some(uniform) => format!("is a {}, not a {}", kind_name(uniform.kind), T::NAME),
+            None => "isn't an active uniform".to_string(),
+        };
+        if self.warned.borrow_mut().insert(name.to_string()) {
+            println!("Warning: {} of shader program {} {}", name, self.program_id, problem);
+        }
     }
 
     pub unsafe fn activate(&self) {
         gl::UseProgram(self.program_id);
     }
+
+    // Run a compute program over `groups` work groups. Memory it writes is only sure to be seen
+    // by what runs next after a `compute::memory_barrier`.
+    pub unsafe fn dispatch(&self, groups: [u32; 3]) {
+        gl::UseProgram(self.program_id);
+        gl::DispatchCompute(groups[0], groups[1], groups[2]);
+    }
+
+    // Run a compute program once for each of `items`, in as many work groups as that takes. The
+    // last groups may run past the end, so the shader should check `gl_GlobalInvocationID`.
+    pub unsafe fn dispatch_items(&self, items: [u32; 3]) {
+        let size = match self.work_group_size {
+            Some(size) => size,
+            None => {
+                println!("Program {} is not a compute program, not dispatching it", self.program_id);
+                return;
+            }
+        };
+        let groups = |i: usize| (items[i] + size[i] - 1) / size[i];
+        self.dispatch([groups(0), groups(1), groups(2)]);
+    }
+
+    // Whether any file the program was built from changed since it was built
+    pub fn has_changed(&self) -> bool {
+        self.watched.iter().any(|(path, built)| {
+            let now = modified(path);
+            // a file missing for a moment while an editor saves it isn't a change yet
+            now.is_some() && now != *built
+        })
+    }
+
+    // Build the program again if any of its files changed, returning whether it did. If that
+    // fails, the previous program is kept and the error returned, and the files are not tried
+    // again until they change again. The program needs activating again after it changed.
+    pub unsafe fn reload_if_changed(&mut self) -> Result<bool, ShaderError> {
+        if self.files.is_empty() || !self.has_changed() {
+            return Ok(false);
+        }
+        let mut builder = Ok(ShaderBuilder::new());
+        if let (Ok(builder), Some(directory)) = (&mut builder, &self.binary_cache) {
+            builder.binary_cache = Some(directory.clone());
+        }
+        for (file, defines) in &self.files {
+            builder = builder.and_then(|mut builder| {
+                builder.defines = defines.clone();
+                builder.attach_file(file)
+            });
+        }
+        match builder.and_then(|builder| builder.link()) {
+            Ok(shader) => {
+                gl::DeleteProgram(self.program_id);
+                *self = shader;
+                Ok(true)
+            }
+            Err(e) => {
+                for (path, built) in &mut self.watched {
+                    *built = modified(path).or(*built);
+                }
+                Err(e)
+            }
+        }
+    }
 }
 
 impl Into<gl::types::GLenum> for ShaderType {
@@ -44,18 +348,20 @@ impl Into<gl::types::GLenum> for ShaderType {
             ShaderType::TessellationControl     => { gl::TESS_CONTROL_SHADER    },
             ShaderType::TessellationEvaluation  => { gl::TESS_EVALUATION_SHADER } ,
             ShaderType::Geometry                => { gl::GEOMETRY_SHADER        },
+            ShaderType::Compute                 => { gl::COMPUTE_SHADER         },
         }
     }
 }
 
 impl ShaderType {
     fn from_ext(ext: &std::ffi::OsStr) -> Result<ShaderType, String> {
-        match ext.to_str().expect("Failed to read extension") {
+        match ext.to_str().unwrap_or_default() {
             "vert" => { Ok(ShaderType::Vertex) },
             "frag" => { Ok(ShaderType::Fragment) },
             "tcs"  => { Ok(ShaderType::TessellationControl) },
             "tes"  => { Ok(ShaderType::TessellationEvaluation) },
             "geom" => { Ok(ShaderType::Geometry) },
+            "comp" => { Ok(ShaderType::Compute) },
             e => { Err(e.to_string()) },
         }
     }
@@ -66,89 +372,234 @@ impl ShaderBuilder {
         ShaderBuilder {
             program_id: gl::CreateProgram(),
             shaders: vec![],
+            files: vec![],
+            defines: vec![],
+            watched: vec![],
+            binary_cache: None,
+            include_directory: PathBuf::from("."),
+            stages: vec![],
+            pending: vec![],
         }
     }
 
-    pub unsafe fn attach_file(self, shader_path: &str) -> ShaderBuilder {
+    // Keep the linked program in `directory`, and load it from there instead of compiling the
+    // shaders when they are built again from the same sources by the same driver, see
+    // `src/program_cache.rs`. It covers the files attached after this.
+    pub fn with_binary_cache(mut self, directory: &str) -> ShaderBuilder {
+        self.binary_cache = Some(PathBuf::from(directory));
+        self
+    }
+
+    // Look for the files that sources given to `compile_shader` include in `directory`, rather than
+    // the working directory. Files include relative to their own directory either way.
+    pub fn with_include_directory(mut self, directory: &str) -> ShaderBuilder {
+        self.include_directory = PathBuf::from(directory);
+        self
+    }
+
+    // Define `name` as `value` in the sources attached after this, as with `#define name value`
+    pub fn define(mut self, name: &str, value: &str) -> ShaderBuilder {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub unsafe fn attach_file(mut self, shader_path: &str) -> Result<ShaderBuilder, ShaderError> {
         let path = Path::new(shader_path);
-        if let Some(extension) = path.extension() {
-            let shader_type = ShaderType::from_ext(extension)
-                .expect("Failed to parse file extension.");
-            let shader_src = std::fs::read_to_string(path)
-                .expect(&format!("Failed to read shader source. {}", shader_path));
-            self.compile_shader(&shader_src, shader_type)
-        } else {
-            panic!("Failed to read extension of file with path: {}", shader_path);
+        let shader_type = match path.extension().map(ShaderType::from_ext) {
+            Some(Ok(shader_type)) => shader_type,
+            _ => {
+                self.discard();
+                return Err(ShaderError::UnknownExtension { path: shader_path.to_string() });
+            }
+        };
+        let preprocessed = match preprocessor::preprocess_file(shader_path, &self.defines) {
+            Ok(preprocessed) => preprocessed,
+            Err(e) => {
+                self.discard();
+                return Err(e);
+            }
+        };
+        self.watched.extend(preprocessed.files.iter().cloned());
+        self.files.push((shader_path.to_string(), self.defines.clone()));
+        self.compile_or_defer(preprocessed, shader_type, Some(shader_path))
+    }
+
+    // Compile a source not read from a file. It includes files from the include directory.
+    pub unsafe fn compile_shader(self, shader_src: &str, shader_type: ShaderType) -> Result<ShaderBuilder, ShaderError> {
+        match preprocessor::preprocess_source(shader_src, "source", &self.include_directory, &self.defines) {
+            Ok(preprocessed) => self.compile_or_defer(preprocessed, shader_type, None),
+            Err(e) => {
+                self.discard();
+                Err(e)
+            }
         }
     }
 
-    pub unsafe fn compile_shader(mut self, shader_src: &str, shader_type: ShaderType) -> ShaderBuilder {
+    // With a cache the stages wait for `link`, which may not need them
+    unsafe fn compile_or_defer(mut self, preprocessed: Preprocessed, shader_type: ShaderType, path: Option<&str>) -> Result<ShaderBuilder, ShaderError> {
+        self.stages.push(shader_type);
+        if self.binary_cache.is_some() {
+            self.pending.push((preprocessed, shader_type, path.map(str::to_string)));
+            return Ok(self);
+        }
+        self.compile(&preprocessed, shader_type, path)
+    }
+
+    unsafe fn compile(mut self, preprocessed: &Preprocessed, shader_type: ShaderType, path: Option<&str>) -> Result<ShaderBuilder, ShaderError> {
         let shader = gl::CreateShader(shader_type.into());
-        let c_str_shader = CString::new(shader_src.as_bytes()).unwrap();
+        // a source with a NUL in it would be cut short there, so let the driver complain about that
+        let c_str_shader = CString::new(preprocessed.source.replace('\0', " ")).unwrap();
         gl::ShaderSource(shader, 1, &c_str_shader.as_ptr(), ptr::null());
         gl::CompileShader(shader);
+        self.shaders.push(shader);
 
-        if !self.check_shader_errors(shader) {
-            panic!("Shader failed to compile.");
+        if let Err(log) = self.check_shader_errors(shader) {
+            self.discard();
+            let lines = parse_line_numbers(&log).into_iter()
+                .filter_map(|line| preprocessed.original_line(line))
+                .map(|(file, line)| (file.to_string(), line))
+                .collect();
+            return Err(ShaderError::Compile { path: path.map(str::to_string), stage: shader_type, log, lines });
         }
 
-        self.shaders.push(shader);
-
-        self
+        Ok(self)
     }
 
-    unsafe fn check_shader_errors(&self, shader_id: u32) -> bool {
+    // The info log if the shader failed to compile
+    unsafe fn check_shader_errors(&self, shader_id: u32) -> Result<(), String> {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
         gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
-            gl::GetShaderInfoLog(
-                shader_id,
-                512,
-                ptr::null_mut(),
-                info_log.as_mut_ptr() as *mut gl::types::GLchar,
-            );
-            println!("ERROR::Shader Compilation Failed!\n{}", String::from_utf8_lossy(&info_log));
-            return false;
+            return Err(info_log(shader_id, gl::GetShaderiv, gl::GetShaderInfoLog));
         }
-        true
+        Ok(())
     }
 
-    unsafe fn check_linker_errors(&self) -> bool {
+    // The info log if the program failed to link
+    unsafe fn check_linker_errors(&self) -> Result<(), String> {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
         gl::GetProgramiv(self.program_id, gl::LINK_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
-            gl::GetProgramInfoLog(
-                self.program_id,
-                512,
-                ptr::null_mut(),
-                info_log.as_mut_ptr() as *mut gl::types::GLchar,
-            );
-            println!("ERROR::SHADER::PROGRAM::COMPILATION_FAILED\n{}", String::from_utf8_lossy(&info_log));
-            return false;
+            return Err(info_log(self.program_id, gl::GetProgramiv, gl::GetProgramInfoLog));
         }
-        true
+        Ok(())
     }
 
-    #[must_use = "The shader program is useless if not stored in a variable."]
-    pub unsafe fn link(self) -> Shader {
+    // Delete everything made so far, after a step failed
+    unsafe fn discard(&self) {
+        for &shader in &self.shaders {
+            gl::DeleteShader(shader);
+        }
+        gl::DeleteProgram(self.program_id);
+    }
+
+    pub unsafe fn link(mut self) -> Result<Shader, ShaderError> {
+        // only stages that were all deferred are known well enough to look up
+        let cache = match &self.binary_cache {
+            Some(directory) if self.shaders.is_empty() && program_cache::supported() => {
+                let driver = program_cache::driver();
+                let stages = self.pending.iter().map(|(preprocessed, shader_type, _)| (*shader_type, preprocessed.source.as_str()));
+                let key = program_cache::key(&driver, stages);
+                Some((directory.clone(), key, driver))
+            }
+            _ => None,
+        };
+        if let Some((directory, key, driver)) = &cache {
+            if program_cache::load(directory, *key, driver, self.program_id) {
+                return Ok(self.finish());
+            }
+            // start over, rather than link a program the driver just refused
+            gl::DeleteProgram(self.program_id);
+            self.program_id = gl::CreateProgram();
+        }
+
+        for (preprocessed, shader_type, path) in std::mem::take(&mut self.pending) {
+            self = self.compile(&preprocessed, shader_type, path.as_deref())?;
+        }
+        if cache.is_some() {
+            gl::ProgramParameteri(self.program_id, gl::PROGRAM_BINARY_RETRIEVABLE_HINT, i32::from(gl::TRUE));
+        }
         for &shader in &self.shaders {
             gl::AttachShader(self.program_id, shader);
         }
         gl::LinkProgram(self.program_id);
 
-        // todo:: use this to make safer abstraction
-        self.check_linker_errors();
-
+        // the program keeps what it needs from the shaders, whether it linked or not
         for &shader in &self.shaders {
             gl::DeleteShader(shader);
         }
 
+        if let Err(log) = self.check_linker_errors() {
+            gl::DeleteProgram(self.program_id);
+            let paths = self.files.into_iter().map(|(path, _)| path).collect();
+            return Err(ShaderError::Link { paths, log });
+        }
+
+        if let Some((directory, key, driver)) = &cache {
+            if let Err(e) = program_cache::save(directory, *key, driver, self.program_id) {
+                println!("Not caching the shader program: {}", e);
+            }
+        }
+        Ok(self.finish())
+    }
+
+    // The shader of the linked program
+    unsafe fn finish(self) -> Shader {
+        // asking any other program for it is an error
+        let work_group_size = if self.stages.contains(&ShaderType::Compute) {
+            let mut size = [0i32; 3];
+            gl::GetProgramiv(self.program_id, gl::COMPUTE_WORK_GROUP_SIZE, size.as_mut_ptr());
+            Some([size[0] as u32, size[1] as u32, size[2] as u32])
+        } else {
+            None
+        };
+        let uniforms = reflect(self.program_id, gl::ACTIVE_UNIFORMS, gl::ACTIVE_UNIFORM_MAX_LENGTH, gl::GetActiveUniform, gl::GetUniformLocation);
+        let attributes = reflect(self.program_id, gl::ACTIVE_ATTRIBUTES, gl::ACTIVE_ATTRIBUTE_MAX_LENGTH, gl::GetActiveAttrib, gl::GetAttribLocation);
+
         Shader {
-            program_id: self.program_id
+            program_id: self.program_id,
+            files: self.files,
+            watched: self.watched,
+            uniforms,
+            attributes,
+            warned: RefCell::new(HashSet::new()),
+            binary_cache: self.binary_cache,
+            work_group_size,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nvidia_logs() {
+        let log = "0(12) : error C0000: syntax error, unexpected identifier\n\
+                   0(3) : warning C7022: unrecognized profile specifier\n\
+                   0(12) : error C1503: undefined variable \"foo\"\n";
+        assert_eq!(parse_line_numbers(log), [12, 3]);
+    }
+
+    #[test]
+    fn mesa_logs() {
+        let log = "0:12(5): error: `foo' undeclared\n\
+                   0:40(17): error: type mismatch\n\
+                   0:12(9): warning: unused variable\n";
+        assert_eq!(parse_line_numbers(log), [12, 40]);
+    }
+
+    #[test]
+    fn amd_and_intel_logs() {
+        let log = "ERROR: 0:12: 'foo' : undeclared identifier\n\
+                   WARNING: 0:7: extension not supported\n\
+                   ERROR: 2 compilation errors.  No code generated.\n";
+        assert_eq!(parse_line_numbers(log), [12, 7]);
+    }
+
+    #[test]
+    fn logs_without_lines() {
+        assert!(parse_line_numbers("").is_empty());
+        assert!(parse_line_numbers("error: too many uniforms\nLink failed.").is_empty());
+    }
+}
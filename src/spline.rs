@@ -0,0 +1,315 @@
+extern crate nalgebra_glm as glm;
+
+use crate::toolbox::Heading;
+use serde::{Deserialize, Serialize};
+
+// Paths through space built from control points, for things to fly along.
+//
+// The path is sampled by distance travelled rather than by curve parameter, so something moving
+// along it at a steady speed doesn't speed up where the control points are far apart. The heading
+// follows the direction of travel: the nose pitches down with speed and up when climbing, and
+// the path banks into turns the way a helicopter would in a coordinated turn.
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SplineKind {
+    CatmullRom, // passes through every control point
+    Bezier,     // cubic segments, each given by an end point and two handles in between
+}
+
+// A path as described in a data file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SplineDescription {
+    pub kind   : SplineKind,
+    pub closed : bool,          // whether the path joins back up with its start
+    pub speed  : f32,           // world units per second
+    pub points : Vec<[f32; 3]>,
+    #[serde(default = "default_bank_factor")]
+    pub bank_factor  : f32,     // 1 banks as steeply as a coordinated turn would
+    #[serde(default = "default_max_bank")]
+    pub max_bank     : f32,     // radians
+    #[serde(default = "default_pitch_factor")]
+    pub pitch_factor : f32,     // radians of nose down per unit of speed
+    #[serde(default = "default_max_pitch")]
+    pub max_pitch    : f32,     // radians
+}
+
+fn default_bank_factor()  -> f32 { 1.0 }
+fn default_max_bank()     -> f32 { 45.0f32.to_radians() }
+fn default_pitch_factor() -> f32 { 0.01 }
+fn default_max_pitch()    -> f32 { 30.0f32.to_radians() }
+
+// How finely each segment is measured when building the distance lookup table
+const SAMPLES_PER_SEGMENT: usize = 64;
+const GRAVITY: f32 = 9.81;
+
+pub struct SplinePath {
+    pub description : SplineDescription,
+    points          : Vec<glm::Vec3>,
+    segments        : usize,
+    lengths         : Vec<f32>, // distance along the path at evenly spaced curve parameters
+    length          : f32,
+}
+
+impl SplinePath {
+    pub fn new(description: SplineDescription) -> Result<SplinePath, String> {
+        let points: Vec<glm::Vec3> = description.points.iter().map(|p| glm::vec3(p[0], p[1], p[2])).collect();
+        let n = points.len();
+        let segments = match (description.kind, description.closed) {
+            (SplineKind::CatmullRom, false) if n >= 2 => n - 1,
+            (SplineKind::CatmullRom, true) if n >= 3 => n,
+            // closed Bezier paths use the first point as the end of their last segment
+            (SplineKind::Bezier, false) if n >= 4 && (n - 1) % 3 == 0 => (n - 1) / 3,
+            (SplineKind::Bezier, true) if n >= 3 && n % 3 == 0 => n / 3,
+            (SplineKind::CatmullRom, _) => {
+                return Err(format!("A Catmull-Rom path needs at least {} points, got {}",
+                    if description.closed { 3 } else { 2 }, n));
+            }
+            (SplineKind::Bezier, _) => {
+                return Err(format!("A {} Bezier path needs {} points, got {}",
+                    if description.closed { "closed" } else { "open" },
+                    if description.closed { "a multiple of 3" } else { "3 per segment plus 1" }, n));
+            }
+        };
+        if description.speed <= 0.0 {
+            return Err("The speed along a path must be positive".to_string());
+        }
+
+        let mut path = SplinePath { description, points, segments, lengths: vec![0.0], length: 0.0 };
+        let steps = segments * SAMPLES_PER_SEGMENT;
+        let mut previous = path.position_at_parameter(0.0);
+        for i in 1..=steps {
+            let position = path.position_at_parameter(i as f32 / SAMPLES_PER_SEGMENT as f32);
+            path.length += glm::distance(&previous, &position);
+            path.lengths.push(path.length);
+            previous = position;
+        }
+        // nowhere to go, and no way to wrap a distance around a closed path
+        if path.length <= 0.0 {
+            return Err("A path must have some length, its points are all in one place".to_string());
+        }
+        Ok(path)
+    }
+
+    pub fn load(path: &str) -> Result<SplinePath, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read path {}: {}", path, e))?;
+        let description: SplineDescription = ron::from_str(&text)
+            .map_err(|e| format!("Failed to parse path {}: {}", path, e))?;
+        SplinePath::new(description).map_err(|e| format!("Invalid path {}: {}", path, e))
+    }
+
+    pub fn length(&self) -> f32 {
+        self.length
+    }
+
+    // A control point, wrapping around closed paths and holding the end points of open ones
+    fn point(&self, index: isize) -> glm::Vec3 {
+        let n = self.points.len() as isize;
+        if self.description.closed {
+            self.points[index.rem_euclid(n) as usize]
+        } else {
+            self.points[index.clamp(0, n - 1) as usize]
+        }
+    }
+
+    // Position at curve parameter `u`, where each whole number is the start of a segment
+    fn position_at_parameter(&self, u: f32) -> glm::Vec3 {
+        let segment = (u.floor() as usize).min(self.segments - 1);
+        let t = u - segment as f32;
+        let (t2, t3) = (t * t, t * t * t);
+        match self.description.kind {
+            SplineKind::CatmullRom => {
+                let i = segment as isize;
+                let (p0, p1, p2, p3) = (self.point(i - 1), self.point(i), self.point(i + 1), self.point(i + 2));
+                (p1 * 2.0
+                    + (p2 - p0) * t
+                    + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+                    + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3) * 0.5
+            }
+            SplineKind::Bezier => {
+                let i = segment as isize * 3;
+                let (p0, p1, p2, p3) = (self.point(i), self.point(i + 1), self.point(i + 2), self.point(i + 3));
+                let s = 1.0 - t;
+                p0 * (s * s * s) + p1 * (3.0 * s * s * t) + p2 * (3.0 * s * t2) + p3 * t3
+            }
+        }
+    }
+
+    // Wrap or clamp a distance along the path to the path itself
+    fn wrap_distance(&self, distance: f32) -> f32 {
+        if self.description.closed {
+            distance.rem_euclid(self.length)
+        } else {
+            distance.clamp(0.0, self.length)
+        }
+    }
+
+    // Position `distance` units along the path
+    pub fn position_at(&self, distance: f32) -> glm::Vec3 {
+        let distance = self.wrap_distance(distance);
+        // the last table entry not past `distance`, and how far we are towards the next one
+        let i = self.lengths.partition_point(|&length| length <= distance).clamp(1, self.lengths.len() - 1);
+        let (before, after) = (self.lengths[i - 1], self.lengths[i]);
+        let fraction = if after > before { (distance - before) / (after - before) } else { 0.0 };
+        self.position_at_parameter((i - 1) as f32 / SAMPLES_PER_SEGMENT as f32
+            + fraction / SAMPLES_PER_SEGMENT as f32)
+    }
+
+    // Where something flying along the path is, and which way it faces, `time` seconds after
+    // setting off from its start
+    pub fn heading_at(&self, time: f32) -> Heading {
+        let description = &self.description;
+        let speed = description.speed;
+        let distance = time * speed;
+        let h = 0.5; // world units either side used to estimate direction and turning
+
+        // stay inside open paths, so we still get a direction at their ends
+        let distance = if description.closed { distance } else { distance.clamp(h, (self.length - h).max(h)) };
+        let (behind, here, ahead) = (self.position_at(distance - h), self.position_at(distance), self.position_at(distance + h));
+        let direction = ahead - behind;
+        let horizontal = glm::length(&direction.xz());
+
+        // the model faces down its negative Z axis
+        let yaw_of = |d: glm::Vec3| (-d.x).atan2(-d.z);
+        let yaw = yaw_of(direction);
+
+        // signed curvature of the horizontal path, positive turning left
+        let mut turn = yaw_of(ahead - here) - yaw_of(here - behind);
+        turn = (turn + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI;
+        let curvature = turn / h;
+        let bank = (speed * speed * curvature / GRAVITY).atan() * description.bank_factor;
+
+        let climb = direction.y.atan2(horizontal);
+        let pitch = climb - description.pitch_factor * speed;
+
+        Heading {
+            x     : here.x,
+            y     : here.y,
+            z     : here.z,
+            roll  : bank.clamp(-description.max_bank, description.max_bank),
+            pitch : pitch.clamp(-description.max_pitch, description.max_pitch),
+            yaw,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn description(kind: SplineKind, closed: bool, points: &[[f32; 3]]) -> SplineDescription {
+        SplineDescription {
+            kind,
+            closed,
+            speed        : 10.0,
+            points       : points.to_vec(),
+            bank_factor  : default_bank_factor(),
+            max_bank     : default_max_bank(),
+            pitch_factor : default_pitch_factor(),
+            max_pitch    : default_max_pitch(),
+        }
+    }
+
+    fn square(size: f32) -> Vec<[f32; 3]> {
+        vec![[0.0, 0.0, 0.0], [size, 0.0, 0.0], [size, 0.0, size], [0.0, 0.0, size]]
+    }
+
+    fn assert_near(a: glm::Vec3, b: glm::Vec3, tolerance: f32) {
+        assert!(glm::distance(&a, &b) < tolerance, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn too_few_points_are_rejected() {
+        let points = square(10.0);
+        // how many points of each kind of path are too few, or don't make whole segments
+        let invalid = [
+            (SplineKind::CatmullRom, false, vec![0, 1]),
+            (SplineKind::CatmullRom, true, vec![0, 1, 2]),
+            (SplineKind::Bezier, false, vec![0, 1, 2, 3]),
+            (SplineKind::Bezier, true, vec![0, 1, 2, 4]),
+        ];
+        for (kind, closed, counts) in invalid {
+            for n in counts {
+                assert!(SplinePath::new(description(kind, closed, &points[..n])).is_err(), "{:?} {} {}", kind, closed, n);
+            }
+        }
+        assert!(SplinePath::new(description(SplineKind::CatmullRom, false, &points[..2])).is_ok());
+        assert!(SplinePath::new(description(SplineKind::CatmullRom, true, &points[..3])).is_ok());
+        assert!(SplinePath::new(description(SplineKind::Bezier, false, &points)).is_ok());
+        assert!(SplinePath::new(description(SplineKind::Bezier, true, &points[..3])).is_ok());
+
+        let mut stopped = description(SplineKind::CatmullRom, true, &points);
+        stopped.speed = 0.0;
+        assert!(SplinePath::new(stopped).is_err());
+    }
+
+    #[test]
+    fn zero_length_paths_are_rejected() {
+        let points = [[1.0, 2.0, 3.0]; 4];
+        assert!(SplinePath::new(description(SplineKind::CatmullRom, true, &points)).is_err());
+        assert!(SplinePath::new(description(SplineKind::CatmullRom, false, &points)).is_err());
+    }
+
+    #[test]
+    fn closed_paths_wrap_and_open_paths_stop() {
+        let closed = SplinePath::new(description(SplineKind::CatmullRom, true, &square(10.0))).unwrap();
+        // rounding the corners of the square bulges out past its sides
+        let length = closed.length();
+        assert!(length > 40.0 && length < 50.0, "{}", length);
+        assert_near(closed.position_at(0.0), glm::vec3(0.0, 0.0, 0.0), 1e-4);
+        assert_near(closed.position_at(length), glm::vec3(0.0, 0.0, 0.0), 1e-3);
+        for distance in [3.0, 17.5, 29.0] {
+            assert_near(closed.position_at(distance + length), closed.position_at(distance), 1e-3);
+            assert_near(closed.position_at(distance - 2.0 * length), closed.position_at(distance), 1e-3);
+        }
+
+        let open = SplinePath::new(description(SplineKind::CatmullRom, false, &square(10.0))).unwrap();
+        assert_near(open.position_at(-5.0), glm::vec3(0.0, 0.0, 0.0), 1e-4);
+        assert_near(open.position_at(open.length() + 5.0), glm::vec3(0.0, 0.0, 10.0), 1e-3);
+    }
+
+    #[test]
+    fn equal_distances_along_the_path_are_equal_in_space() {
+        // control points far apart and close together, which a curve parameter would cross at
+        // very different speeds
+        let points = [[0.0, 0.0, 0.0], [2.0, 0.0, 1.0], [4.0, 0.5, 1.5], [20.0, 2.0, 5.0], [40.0, 0.0, 0.0], [42.0, 0.0, -1.0]];
+        for kind in [SplineKind::CatmullRom, SplineKind::Bezier] {
+            let path = SplinePath::new(description(kind, kind == SplineKind::Bezier, &points)).unwrap();
+            let steps = 100;
+            let step = path.length() / steps as f32;
+            for i in 0..steps {
+                // finer than a step, so sharp bends in the curve are measured along it
+                let travelled: f32 = (0..10).map(|j| {
+                    let distance = (i as f32 + j as f32 / 10.0) * step;
+                    glm::distance(&path.position_at(distance), &path.position_at(distance + step / 10.0))
+                }).sum();
+                assert!((travelled - step).abs() < step * 0.02, "{:?} step {}: {} instead of {}", kind, i, travelled, step);
+            }
+        }
+    }
+
+    #[test]
+    fn bank_and_pitch_stay_within_their_limits() {
+        // a tight, steep spiral, flown fast
+        let points: Vec<[f32; 3]> = (0..12).map(|i| {
+            let angle = i as f32 / 12.0 * std::f32::consts::TAU;
+            [5.0 * angle.cos(), if i < 6 { i as f32 * 4.0 } else { (12 - i) as f32 * 4.0 }, 5.0 * angle.sin()]
+        }).collect();
+        let mut description = description(SplineKind::CatmullRom, true, &points);
+        description.speed = 40.0;
+        description.max_bank = 0.3;
+        description.max_pitch = 0.2;
+        let path = SplinePath::new(description).unwrap();
+
+        let (mut steepest_bank, mut steepest_pitch) = (0.0f32, 0.0f32);
+        for i in 0..500 {
+            let heading = path.heading_at(i as f32 * 0.01);
+            assert!(heading.roll.abs() <= 0.3 && heading.pitch.abs() <= 0.2, "{} {}", heading.roll, heading.pitch);
+            steepest_bank = steepest_bank.max(heading.roll.abs());
+            steepest_pitch = steepest_pitch.max(heading.pitch.abs());
+        }
+        // the limits are reached, rather than the turn being gentle
+        assert_eq!(steepest_bank, 0.3);
+        assert_eq!(steepest_pitch, 0.2);
+    }
+}
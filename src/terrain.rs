@@ -0,0 +1,80 @@
+use crate::compute::{self, Barrier};
+use crate::shader::{Sampler, Shader};
+
+// Detail for the tessellated terrain.
+//
+// The terrain mesh is coarse, so it is drawn as patches (see `Renderer::draw_patches`) which
+// `shaders/terrain.tcs` splits finer the closer they are to the camera, and `shaders/terrain.tes`
+// moves the new vertices along the surface normal by a heightmap tiled across the ground. The
+// heightmap is made once at startup by a compute program, `shaders/terrain_heightmap.comp`.
+//
+// The collision mesh is the coarse one, and the heightmap averages to zero, so the helicopters
+// land on the ground as the player sees it give or take half of `TerrainDetail::height_scale`.
+
+pub const HEIGHTMAP_UNIT : u32 = 0;       // the texture unit the terrain shaders read it from
+const HEIGHTMAP_IMAGE_UNIT : u32 = 0;     // as in `shaders/terrain_heightmap.comp`
+
+// Vertices per patch the terrain shaders take: triangles, as `layout (vertices = 3)` in
+// `shaders/terrain.tcs` and `layout (triangles)` in `terrain.tes` say
+pub const PATCH_SIZE : i32 = 3;
+
+// A square single channel texture of heights from 0 to 1, repeating at its edges
+pub struct Heightmap {
+    pub texture_id : u32,
+    pub size       : u32, // texels along each side
+}
+
+// How the terrain shaders tessellate and displace the terrain
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainDetail {
+    pub tessellation : f32, // segments an edge is split into per radian it covers as seen from the camera
+    pub height_scale : f32, // world units between the lowest and highest point of the heightmap
+    pub tile_size    : f32, // world units covered by one repeat of the heightmap
+}
+
+impl Default for TerrainDetail {
+    fn default() -> Self {
+        TerrainDetail {
+            tessellation : 40.0,
+            height_scale : 0.6,
+            tile_size    : 16.0,
+        }
+    }
+}
+
+impl Heightmap {
+    // Run `generator` once for every texel of a new `size` by `size` heightmap
+    pub unsafe fn generate(size: u32, generator: &Shader) -> Self {
+        let mut texture_id: u32 = 0;
+        gl::GenTextures(1, &mut texture_id);
+        gl::BindTexture(gl::TEXTURE_2D, texture_id);
+        gl::TexStorage2D(gl::TEXTURE_2D, 1, gl::R32F, size as i32, size as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+
+        gl::BindImageTexture(HEIGHTMAP_IMAGE_UNIT, texture_id, 0, gl::FALSE, 0, gl::WRITE_ONLY, gl::R32F);
+        generator.dispatch_items([size, size, 1]);
+        compute::memory_barrier(&[Barrier::Textures]);
+
+        Heightmap { texture_id, size }
+    }
+
+    pub unsafe fn bind(&self, unit: u32) {
+        gl::ActiveTexture(gl::TEXTURE0 + unit);
+        gl::BindTexture(gl::TEXTURE_2D, self.texture_id);
+    }
+}
+
+impl TerrainDetail {
+    // Give the terrain program these settings and the heightmap on `HEIGHTMAP_UNIT`. A reloaded
+    // program needs them again.
+    pub unsafe fn apply(&self, shader: &Shader) {
+        shader.set_uniform("heightmap", Sampler(HEIGHTMAP_UNIT as i32));
+        shader.set_uniform("tessellationDetail", self.tessellation);
+        shader.set_uniform("heightScale", self.height_scale);
+        shader.set_uniform("heightmapTile", self.tile_size);
+    }
+}
@@ -3,12 +3,24 @@ use std::f64::consts::PI;
 
 pub struct Heading {
     pub x     : f32,
+    pub y     : f32,
     pub z     : f32,
     pub roll  : f32, // measured in radians
     pub pitch : f32, // measured in radians
     pub yaw   : f32, // measured in radians
 }
 
+impl Heading {
+    // The angles for `SceneNode::rotation` that turn to the heading first, then pitch and roll
+    // about the turned axes
+    pub fn rotation(&self) -> glm::Vec3 {
+        let rotation = glm::quat_angle_axis(self.yaw, &glm::vec3(0.0, 1.0, 0.0))
+            * glm::quat_angle_axis(self.pitch, &glm::vec3(1.0, 0.0, 0.0))
+            * glm::quat_angle_axis(self.roll, &glm::vec3(0.0, 0.0, 1.0));
+        crate::scene_graph::quat_to_euler(&rotation)
+    }
+}
+
 pub fn simple_heading_animation(time: f32) -> Heading {
     let t             = time as f64;
     let step          = 0.05f64;
@@ -28,6 +40,7 @@ pub fn simple_heading_animation(time: f32) -> Heading {
 
     Heading {
         x     : xpos  as f32,
+        y     : 0.0,
         z     : zpos  as f32,
         roll  : roll  as f32,
         pitch : pitch as f32,
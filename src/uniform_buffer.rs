@@ -0,0 +1,107 @@
+extern crate nalgebra_glm as glm;
+
+use crate::scene::{Light, LightKind};
+use std::marker::PhantomData;
+use std::mem;
+
+// Uniform buffers for what every program needs each frame: the camera, the time and the lights.
+//
+// Each block is bound to a fixed binding point, which the shaders name in their declarations in
+// `shaders/frame.glsl`, so every program that includes it shares the same buffers and nothing
+// needs setting per program. The Rust structs below mirror those declarations in the std140
+// layout: vec3s take the space of a vec4 unless a float follows them, and arrays of structs are
+// aligned to 16 bytes. Their sizes are checked at compile time.
+
+pub const CAMERA_BINDING : u32 = 0;
+pub const LIGHTS_BINDING : u32 = 1;
+
+pub const MAX_LIGHTS : usize = 8; // as in `shaders/frame.glsl`
+
+// `uniform Camera`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CameraBlock {
+    pub view            : glm::Mat4,
+    pub projection      : glm::Mat4,
+    pub view_projection : glm::Mat4,
+    pub camera_position : [f32; 3],
+    pub time            : f32,      // simulated seconds
+}
+
+// `struct Light`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LightData {
+    pub position  : [f32; 4], // w is 1 for point lights and 0 for directional ones
+    pub direction : [f32; 4], // the way a directional light shines
+    pub color     : [f32; 4], // times the intensity
+}
+
+// `uniform Lights`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct LightsBlock {
+    pub count   : i32,
+    _padding    : [i32; 3],
+    pub lights  : [LightData; MAX_LIGHTS],
+}
+
+const _: () = assert!(mem::size_of::<CameraBlock>() == 3 * 64 + 16);
+const _: () = assert!(mem::size_of::<LightData>() == 48);
+const _: () = assert!(mem::size_of::<LightsBlock>() == 16 + MAX_LIGHTS * 48);
+
+impl LightData {
+    pub fn from_light(light: &Light) -> Self {
+        let w = if light.kind == LightKind::Point { 1.0 } else { 0.0 };
+        let [x, y, z] = light.position;
+        let direction = glm::vec3(light.direction[0], light.direction[1], light.direction[2]);
+        // point lights may leave it out
+        let direction = if direction.norm() > 0.0 { direction.normalize() } else { direction };
+        let [r, g, b] = light.color;
+        LightData {
+            position  : [x, y, z, w],
+            direction : [direction.x, direction.y, direction.z, 0.0],
+            color     : [r * light.intensity, g * light.intensity, b * light.intensity, 1.0],
+        }
+    }
+}
+
+impl LightsBlock {
+    // The first `MAX_LIGHTS` of `lights`
+    pub fn new(lights: &[Light]) -> Self {
+        if lights.len() > MAX_LIGHTS {
+            println!("Only the first {} of {} lights are used", MAX_LIGHTS, lights.len());
+        }
+        let mut block = LightsBlock { count: 0, _padding: [0; 3], lights: [LightData::default(); MAX_LIGHTS] };
+        for (data, light) in block.lights.iter_mut().zip(lights) {
+            *data = LightData::from_light(light);
+            block.count += 1;
+        }
+        block
+    }
+}
+
+// A buffer holding one `T`, bound to a binding point for every program to read
+pub struct UniformBuffer<T> {
+    pub buffer_id : u32,
+    pub binding   : u32,
+    contents      : PhantomData<T>,
+}
+
+impl<T> UniformBuffer<T> {
+    pub unsafe fn new(binding: u32) -> Self {
+        let mut buffer_id: u32 = 0;
+        gl::GenBuffers(1, &mut buffer_id);
+        gl::BindBuffer(gl::UNIFORM_BUFFER, buffer_id);
+        gl::BufferData(gl::UNIFORM_BUFFER, mem::size_of::<T>() as isize, std::ptr::null(), gl::DYNAMIC_DRAW);
+        gl::BindBufferBase(gl::UNIFORM_BUFFER, binding, buffer_id);
+        gl::BindBuffer(gl::UNIFORM_BUFFER, 0);
+        UniformBuffer { buffer_id, binding, contents: PhantomData }
+    }
+
+    pub unsafe fn update(&self, data: &T) {
+        gl::BindBuffer(gl::UNIFORM_BUFFER, self.buffer_id);
+        gl::BufferSubData(gl::UNIFORM_BUFFER, 0, mem::size_of::<T>() as isize, data as *const T as *const _);
+        gl::BindBuffer(gl::UNIFORM_BUFFER, 0);
+    }
+}